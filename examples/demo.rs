@@ -1,16 +1,15 @@
 use avian3d::prelude::*;
 use bevy::prelude::*;
 use bevy_midi_graph::{
-    MidiGraphAudioContext, MidiGraphPlugin,
-    midi::event::{CueData, Event, EventTarget, EventTiming, Message, MessageSender},
+    MidiGraphAudioContext, MidiGraphCommand, MidiGraphPlugin,
+    midi::event::{CueData, Event},
 };
-use std::sync::Arc;
 
 const PLAYER_VELOCITY: f32 = 3.0;
 
-const MIDI_CONFIG: &str = "demo/graph.json";
+const MIDI_CONFIG: &str = "demo/graph.midigraph.json";
 const PROGRAM_NO: usize = 1;
-const MIDI_NODE_ID: u64 = 101;
+const MIDI_NODE_NAME: &str = "music";
 const DEFAULT_ANCHOR: u32 = 0;
 const ENTER_TENSION_ANCHOR: u32 = 1;
 
@@ -19,7 +18,11 @@ struct Player;
 
 pub fn main() {
     App::new()
-        .add_plugins((DefaultPlugins, PhysicsPlugins::default(), MidiGraphPlugin))
+        .add_plugins((
+            DefaultPlugins,
+            PhysicsPlugins::default(),
+            MidiGraphPlugin::default(),
+        ))
         .insert_resource(GlobalAmbientLight {
             color: Color::WHITE,
             brightness: 1000.0,
@@ -129,7 +132,7 @@ fn move_character(
 /// possible to be in the wrong section for what's going on in the game. Ideally we'd wait for the
 /// asset to load before enabling gameplay, but let's keep this example simple.
 fn check_intersections(
-    mut audio_context: ResMut<MidiGraphAudioContext>,
+    mut graph_commands: MessageWriter<MidiGraphCommand>,
     player_query: Query<Entity, With<Player>>,
     sensor_query: Query<Entity, With<Sensor>>,
     mut collision_started_events: MessageReader<CollisionStart>,
@@ -157,15 +160,10 @@ fn check_intersections(
     };
     if *current_anchor != desired_track {
         *current_anchor = desired_track;
-        let channel: Arc<MessageSender> = audio_context.get_event_sender();
-        let send = channel.send(Message {
-            target: EventTarget::SpecificNode(MIDI_NODE_ID),
-            event: Event::CueData(CueData::SeekWhenIdeal(desired_track)),
-            timing: EventTiming::Imprecise,
-        });
-        if let Err(err) = send {
-            panic!("{:?}", err);
-        }
+        graph_commands.write(MidiGraphCommand::to_named_node(
+            MIDI_NODE_NAME,
+            Event::CueData(CueData::SeekWhenIdeal(desired_track)),
+        ));
     }
     Ok(())
 }
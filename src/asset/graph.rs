@@ -1,71 +1,276 @@
 use crate::{
-    asset::{midi::MidiFileSource, sf2::Sf2FileSource, wave::WaveFileSource},
-    AssetType, GraphAssetLoader,
+    asset::{
+        include::{resolve_includes, GraphReader},
+        midi::MidiFileSource,
+        names::{assign_midi_node_ids, assign_named_nodes},
+        sf2::Sf2FileSource,
+        validate::{find_file_source_path, validate_graph, GraphLoadError, GraphValidationError},
+        wave::WaveFileSource,
+    },
+    AssetType, GraphAssetLoader, NodeRef,
 };
 use bevy::{
     asset::{io::Reader, AssetLoader, LoadContext},
     prelude::*,
 };
 use midi_graph::abstraction::ChildConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
 
 #[derive(Asset, TypePath)]
 pub struct MidiGraph {
     pub config: ChildConfig,
+    // Node type names, such as "Midi", of every node given an explicit node_id in the graph
+    pub node_types: HashMap<u64, String>,
+    // File sources of the Midi nodes given an explicit node_id
+    pub midi_sources: HashMap<u64, MidiNodeSource>,
+    // Node ids of the nodes given a name in the graph
+    pub node_names: HashMap<String, u64>,
     pub midi_assets: Vec<Handle<MidiFileSource>>,
     pub sf2_assets: Vec<Handle<Sf2FileSource>>,
     pub wave_assets: Vec<Handle<WaveFileSource>>,
 }
 
+pub struct MidiNodeSource {
+    pub path: String,
+    pub track_index: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MidiGraphLoaderSettings {
+    /// Check the graph for mistakes such as duplicate node ids before building it. Disabling
+    /// this skips the checks for graphs that are known to be valid.
+    pub validate: bool,
+}
+
+impl Default for MidiGraphLoaderSettings {
+    fn default() -> Self {
+        Self { validate: true }
+    }
+}
+
 #[derive(TypePath, Default)]
 pub struct MidiGraphLoader {}
 
+impl MidiGraphLoader {
+    pub fn file_extensions<'a>() -> &'a [&'static str] {
+        &["midigraph.json", "mgraph"]
+    }
+}
+
 impl AssetLoader for MidiGraphLoader {
     type Asset = MidiGraph;
-    type Settings = ();
-    type Error = midi_graph::Error;
+    type Settings = MidiGraphLoaderSettings;
+    type Error = GraphLoadError;
     async fn load<'a>(
         &'a self,
         reader: &mut dyn Reader,
-        _settings: &(),
+        settings: &MidiGraphLoaderSettings,
         load_context: &mut LoadContext<'_>,
     ) -> Result<Self::Asset, Self::Error> {
-        println!("Starting graph load...");
+        debug!("Loading graph {}", load_context.asset_path());
         let mut bytes = vec![];
         reader.read_to_end(&mut bytes).await?;
-        let root_config: ChildConfig = serde_json::from_slice(&bytes)?;
-        println!("Core graph loaded");
-
-        let mut midi_assets = vec![];
-        let mut sf2_assets = vec![];
-        let mut wave_assets = vec![];
-        ChildConfig::traverse_config_tree(&root_config, &mut |config: &ChildConfig| {
-            if let Some(sub_asset_path) = config.0.asset_source() {
-                let sub_asset_path = sub_asset_path.to_owned();
-                match GraphAssetLoader::infer_asset_type(&sub_asset_path).unwrap() {
-                    AssetType::Midi => {
-                        println!("Queuing MIDI asset...");
-                        let handle = load_context.load(sub_asset_path);
-                        midi_assets.push(handle);
-                    }
-                    AssetType::SoundFont => {
-                        println!("Queuing SoundFont asset...");
-                        let handle = load_context.load(sub_asset_path);
-                        sf2_assets.push(handle);
-                    }
-                    AssetType::Wave => {
-                        println!("Queuing Wave asset...");
-                        let handle = load_context.load(sub_asset_path);
-                        wave_assets.push(handle);
-                    }
-                }
-            };
-        });
+        let value = serde_json::from_slice::<Value>(&bytes)?;
+        build_graph(value, settings, load_context).await
+    }
+
+    fn extensions(&self) -> &[&str] {
+        Self::file_extensions()
+    }
+}
+
+// Build a graph asset from its parsed document, whichever format it was written in, queuing its
+// sub-assets as dependencies.
+pub(crate) async fn build_graph(
+    mut value: Value,
+    settings: &MidiGraphLoaderSettings,
+    load_context: &mut LoadContext<'_>,
+) -> Result<MidiGraph, GraphLoadError> {
+    let root_path = load_context
+        .asset_path()
+        .path()
+        .to_string_lossy()
+        .into_owned();
+    resolve_includes(&mut value, root_path, load_context).await?;
+    let prepared = prepare_graph(value, settings)?;
+    debug!(
+        "Graph {} prepared, queuing its assets",
+        load_context.asset_path()
+    );
+
+    let mut graph = prepared.graph;
+    for (asset_type, sub_asset_path) in prepared.sub_assets {
+        match asset_type {
+            AssetType::Midi => {
+                debug!("Queuing MIDI asset {}", sub_asset_path);
+                let handle = load_context.load(sub_asset_path);
+                graph.midi_assets.push(handle);
+            }
+            AssetType::SoundFont => {
+                debug!("Queuing SoundFont asset {}", sub_asset_path);
+                let handle = load_context.load(sub_asset_path);
+                graph.sf2_assets.push(handle);
+            }
+            AssetType::Wave => {
+                debug!("Queuing Wave asset {}", sub_asset_path);
+                let handle = load_context.load(sub_asset_path);
+                graph.wave_assets.push(handle);
+            }
+        }
+    }
+    Ok(graph)
+}
+
+// Load a graph file without an asset server, reading it and any graphs it includes through the
+// given reader. The graph goes through the same steps as in the asset loaders, but its
+// sub-assets are not loaded, so its asset handles are left empty.
+pub(crate) fn load_graph_file(
+    path: &str,
+    reader: &mut dyn GraphReader,
+    settings: &MidiGraphLoaderSettings,
+) -> Result<MidiGraph, GraphLoadError> {
+    bevy::tasks::block_on(async {
+        let bytes = reader.read(path).await.map_err(|err| {
+            GraphLoadError::Read(std::io::Error::other(format!(
+                "Cannot read graph {}: {}",
+                path, err
+            )))
+        })?;
+        let mut value = parse_graph_document(path, &bytes)?;
+        resolve_includes(&mut value, path.to_owned(), reader).await?;
+        Ok::<_, GraphLoadError>(prepare_graph(value, settings)?.graph)
+    })
+}
 
-        Ok(MidiGraph {
+// A graph with its includes resolved, turned into a node config and indexed, with the paths of
+// the sub-assets its nodes need still to be loaded.
+pub(crate) struct PreparedGraph {
+    pub graph: MidiGraph,
+    pub sub_assets: Vec<(AssetType, String)>,
+}
+
+pub(crate) fn prepare_graph(
+    mut value: Value,
+    settings: &MidiGraphLoaderSettings,
+) -> Result<PreparedGraph, GraphLoadError> {
+    if settings.validate {
+        validate_graph(&value)?;
+    }
+    let node_names = assign_named_nodes(&mut value)?;
+    assign_midi_node_ids(&mut value);
+    let root_config: ChildConfig = serde_json::from_value(value.clone())?;
+    let mut node_types = HashMap::new();
+    let mut midi_sources = HashMap::new();
+    index_nodes(&value, &mut node_types, &mut midi_sources);
+
+    // Validation checks every file path it finds, but the node configs have the final say
+    // on which paths are sub-assets
+    let mut sub_assets = vec![];
+    let mut unknown_assets = vec![];
+    ChildConfig::traverse_config_tree(&root_config, &mut |config: &ChildConfig| {
+        if let Some(sub_asset_path) = config.0.asset_source() {
+            match GraphAssetLoader::infer_asset_type(sub_asset_path) {
+                Ok(asset_type) => sub_assets.push((asset_type, sub_asset_path.to_owned())),
+                Err(err) => unknown_assets.push(GraphValidationError {
+                    json_path: find_file_source_path(&value, sub_asset_path).unwrap_or_default(),
+                    message: format!("{:?}", err),
+                }),
+            }
+        };
+    });
+
+    if !unknown_assets.is_empty() {
+        return Err(GraphLoadError::Invalid(unknown_assets));
+    }
+
+    Ok(PreparedGraph {
+        graph: MidiGraph {
             config: root_config,
-            midi_assets,
-            sf2_assets,
-            wave_assets,
-        })
+            node_types,
+            midi_sources,
+            node_names,
+            midi_assets: vec![],
+            sf2_assets: vec![],
+            wave_assets: vec![],
+        },
+        sub_assets,
+    })
+}
+
+// Parse a graph document in the format given by its file extension
+#[cfg_attr(not(any(feature = "ron", feature = "yaml")), allow(unused_variables))]
+pub(crate) fn parse_graph_document(path: &str, bytes: &[u8]) -> Result<Value, GraphLoadError> {
+    #[cfg(feature = "ron")]
+    if path.ends_with(".ron") {
+        return crate::asset::graph_formats::parse_ron_graph(bytes);
+    }
+    #[cfg(feature = "yaml")]
+    if path.ends_with(".yaml") || path.ends_with(".yml") {
+        return crate::asset::graph_formats::parse_yaml_graph(bytes);
+    }
+    Ok(serde_json::from_slice::<Value>(bytes)?)
+}
+
+impl MidiGraph {
+    // Node id of the node given this name in the graph
+    pub fn node_id(&self, name: &str) -> Option<u64> {
+        self.node_names.get(name).copied()
+    }
+
+    // Node id of a node given by id or name. Ids are passed through as they are, since nodes
+    // don't need to be named or indexed to receive events.
+    pub fn resolve_node(&self, node: &NodeRef) -> Option<u64> {
+        match node {
+            NodeRef::Id(node_id) => Some(*node_id),
+            NodeRef::Name(name) => self.node_id(name),
+        }
+    }
+
+    pub fn node_ids_of_type<'a>(&'a self, node_type: &'a str) -> impl Iterator<Item = u64> + 'a {
+        self.node_types
+            .iter()
+            .filter(move |(_, this_type)| this_type.as_str() == node_type)
+            .map(|(node_id, _)| *node_id)
+    }
+}
+
+fn index_nodes(
+    value: &Value,
+    node_types: &mut HashMap<u64, String>,
+    midi_sources: &mut HashMap<u64, MidiNodeSource>,
+) {
+    match value {
+        Value::Object(map) => {
+            let node_type = map.get("type").and_then(Value::as_str);
+            let node_id = map.get("node_id").and_then(Value::as_u64);
+            if let (Some(node_type), Some(node_id)) = (node_type, node_id) {
+                node_types.insert(node_id, node_type.to_owned());
+                let file_source = map.get("source").and_then(|source| source.get("FilePath"));
+                let path = file_source
+                    .and_then(|file_source| file_source.get("path"))
+                    .and_then(Value::as_str);
+                if let (Some(path), "Midi") = (path, node_type) {
+                    let track_index = file_source
+                        .and_then(|file_source| file_source.get("track_index"))
+                        .and_then(Value::as_u64)
+                        .unwrap_or(0);
+                    midi_sources.insert(
+                        node_id,
+                        MidiNodeSource {
+                            path: path.to_owned(),
+                            track_index: track_index as usize,
+                        },
+                    );
+                }
+            }
+            map.values()
+                .for_each(|value| index_nodes(value, node_types, midi_sources));
+        }
+        Value::Array(values) => values
+            .iter()
+            .for_each(|value| index_nodes(value, node_types, midi_sources)),
+        _ => {}
     }
 }
@@ -0,0 +1,604 @@
+use crate::{
+    asset::{
+        graph::{build_graph, parse_graph_document, MidiGraphLoaderSettings},
+        validate::GraphLoadError,
+    },
+    MidiGraph,
+};
+use bevy::{
+    asset::{io::Reader, AssetLoader, LoadContext},
+    prelude::*,
+};
+use serde_json::Value;
+
+// Graphs in other formats are read into the same document structure as JSON graphs, so they
+// are validated and indexed the same way. Nodes are written as maps, or RON structs, with a
+// "type" key, just as in JSON.
+
+/// Loads graphs written in RON, with the extension `.midigraph.ron`.
+#[cfg(feature = "ron")]
+#[derive(TypePath, Default)]
+pub struct MidiGraphRonLoader {}
+
+#[cfg(feature = "ron")]
+impl AssetLoader for MidiGraphRonLoader {
+    type Asset = MidiGraph;
+    type Settings = MidiGraphLoaderSettings;
+    type Error = GraphLoadError;
+    async fn load<'a>(
+        &'a self,
+        reader: &mut dyn Reader,
+        settings: &MidiGraphLoaderSettings,
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).await?;
+        let path = load_context
+            .asset_path()
+            .path()
+            .to_string_lossy()
+            .into_owned();
+        let value = parse_graph_document(&path, &bytes)?;
+        build_graph(value, settings, load_context).await
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["midigraph.ron"]
+    }
+}
+
+/// Loads graphs written in YAML, with the extension `.midigraph.yaml` or `.midigraph.yml`.
+#[cfg(feature = "yaml")]
+#[derive(TypePath, Default)]
+pub struct MidiGraphYamlLoader {}
+
+#[cfg(feature = "yaml")]
+impl AssetLoader for MidiGraphYamlLoader {
+    type Asset = MidiGraph;
+    type Settings = MidiGraphLoaderSettings;
+    type Error = GraphLoadError;
+    async fn load<'a>(
+        &'a self,
+        reader: &mut dyn Reader,
+        settings: &MidiGraphLoaderSettings,
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).await?;
+        let path = load_context
+            .asset_path()
+            .path()
+            .to_string_lossy()
+            .into_owned();
+        let value = parse_graph_document(&path, &bytes)?;
+        build_graph(value, settings, load_context).await
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["midigraph.yaml", "midigraph.yml"]
+    }
+}
+
+// RON writes enum variants with their name in front, as in `FilePath("song.mid")`, but reading
+// RON without knowing the types it holds drops the name. Variants are rewritten into the map
+// form that JSON graphs use, `{"FilePath": "song.mid"}`, before the document is parsed. Nodes
+// can be written as named structs too, as in `Midi(type: "Midi", ...)`, and are told apart from
+// struct variants by their "type" field.
+#[cfg(feature = "ron")]
+pub(crate) fn parse_ron_graph(bytes: &[u8]) -> Result<Value, GraphLoadError> {
+    let source = std::str::from_utf8(bytes).map_err(|err| GraphLoadError::Parse(Box::new(err)))?;
+    let source = RonVariantTagger::new(source)
+        .tag_document()
+        .map_err(|message| GraphLoadError::Parse(message.into()))?;
+    ron::de::from_str::<Value>(&source).map_err(|err| GraphLoadError::Parse(Box::new(err)))
+}
+
+#[cfg(feature = "ron")]
+struct RonVariantTagger {
+    chars: Vec<char>,
+    position: usize,
+}
+
+#[cfg(feature = "ron")]
+impl RonVariantTagger {
+    // Identifiers that RON reads as values rather than as unit variants
+    const VALUE_IDENTIFIERS: [&'static str; 6] = ["true", "false", "None", "Some", "inf", "NaN"];
+
+    fn new(source: &str) -> Self {
+        Self {
+            chars: source.chars().collect(),
+            position: 0,
+        }
+    }
+
+    fn tag_document(mut self) -> Result<String, String> {
+        let mut output = String::new();
+        // Extension attributes such as #![enable(implicit_some)] are kept as written
+        while self.skip_trivia(&mut output) && self.starts_with("#!") {
+            let end = self.find_from(self.position, ']')?;
+            output.extend(&self.chars[self.position..=end]);
+            self.position = end + 1;
+        }
+        let elements = self.tag_until(None)?;
+        output.push_str(&elements.concat());
+        Ok(output)
+    }
+
+    // Rewrite up to the given closing bracket, or to the end of the document, returning the
+    // rewritten text of each element between commas
+    fn tag_until(&mut self, close: Option<char>) -> Result<Vec<String>, String> {
+        let mut elements = vec![];
+        let mut element = String::new();
+        loop {
+            let Some(c) = self.peek(0) else {
+                if let Some(close) = close {
+                    return Err(format!(
+                        "Expected '{}' before the end of the document",
+                        close
+                    ));
+                }
+                elements.push(element);
+                return Ok(elements);
+            };
+            match c {
+                _ if Some(c) == close => {
+                    self.position += 1;
+                    elements.push(element);
+                    return Ok(elements);
+                }
+                ')' | ']' | '}' => {
+                    return Err(format!("Unexpected '{}' at {}", c, self.location()));
+                }
+                ',' if close.is_some() => {
+                    self.position += 1;
+                    elements.push(std::mem::take(&mut element));
+                }
+                '(' | '[' | '{' => {
+                    self.position += 1;
+                    let close = match c {
+                        '(' => ')',
+                        '[' => ']',
+                        _ => '}',
+                    };
+                    let inner = self.tag_until(Some(close))?;
+                    element.push(c);
+                    element.push_str(&inner.join(","));
+                    element.push(close);
+                }
+                '"' => self.copy_string(&mut element)?,
+                'b' if self.peek(1) == Some('"') => {
+                    self.position += 1;
+                    element.push('b');
+                    self.copy_string(&mut element)?;
+                }
+                'r' | 'b' if self.is_raw_string_start() => self.copy_raw_string(&mut element)?,
+                '\'' => self.copy_char(&mut element)?,
+                '/' if matches!(self.peek(1), Some('/' | '*')) => {
+                    self.skip_trivia(&mut element);
+                }
+                _ if c.is_ascii_digit() => self.copy_number(&mut element),
+                _ if is_identifier_start(c) => self.tag_identifier(&mut element)?,
+                _ => {
+                    self.position += 1;
+                    element.push(c);
+                }
+            }
+        }
+    }
+
+    fn tag_identifier(&mut self, output: &mut String) -> Result<(), String> {
+        let identifier = self.read_identifier();
+        let mut lookahead = self.position;
+        self.skip_trivia_from(&mut lookahead);
+        let next = self.chars.get(lookahead).copied();
+        let is_field_name = next == Some(':') && self.chars.get(lookahead + 1) != Some(&':');
+        let name = identifier.trim_start_matches("r#");
+        if is_field_name || Self::VALUE_IDENTIFIERS.contains(&name) {
+            output.push_str(&identifier);
+            return Ok(());
+        }
+        if next != Some('(') {
+            output.push_str(&format!("\"{}\"", name));
+            return Ok(());
+        }
+        self.position = lookahead + 1;
+        let is_struct = self.starts_with_field_name();
+        let mut elements = self.tag_until(Some(')'))?;
+        if is_struct {
+            let fields = elements.join(",");
+            match elements.iter().any(|element| is_type_field(element)) {
+                true => output.push_str(&format!("({})", fields)),
+                false => output.push_str(&format!("{{\"{}\": ({})}}", name, fields)),
+            }
+            return Ok(());
+        }
+        // A trailing comma leaves an element with nothing but whitespace in it
+        if elements
+            .last()
+            .is_some_and(|element| element.trim().is_empty())
+        {
+            elements.pop();
+        }
+        match elements.len() {
+            0 => output.push_str(&format!("\"{}\"", name)),
+            1 => output.push_str(&format!("{{\"{}\": {}}}", name, elements[0])),
+            _ => output.push_str(&format!("{{\"{}\": [{}]}}", name, elements.join(","))),
+        }
+        Ok(())
+    }
+
+    fn starts_with_field_name(&self) -> bool {
+        let mut lookahead = self.position;
+        self.skip_trivia_from(&mut lookahead);
+        if self.chars[lookahead..].starts_with(&['r', '#']) {
+            lookahead += 2;
+        }
+        if !self
+            .chars
+            .get(lookahead)
+            .copied()
+            .is_some_and(is_identifier_start)
+        {
+            return false;
+        }
+        while self
+            .chars
+            .get(lookahead)
+            .copied()
+            .is_some_and(is_identifier_char)
+        {
+            lookahead += 1;
+        }
+        self.skip_trivia_from(&mut lookahead);
+        self.chars.get(lookahead) == Some(&':') && self.chars.get(lookahead + 1) != Some(&':')
+    }
+
+    fn read_identifier(&mut self) -> String {
+        let start = self.position;
+        if self.starts_with("r#") {
+            self.position += 2;
+        }
+        while self.peek(0).is_some_and(is_identifier_char) {
+            self.position += 1;
+        }
+        self.chars[start..self.position].iter().collect()
+    }
+
+    fn copy_string(&mut self, output: &mut String) -> Result<(), String> {
+        let start = self.position;
+        self.position += 1;
+        loop {
+            match self.peek(0) {
+                None => return Err(format!("Unterminated string starting at {}", start)),
+                Some('\\') => self.position += 2,
+                Some('"') => break,
+                Some(_) => self.position += 1,
+            }
+        }
+        self.position += 1;
+        output.extend(&self.chars[start..self.position]);
+        Ok(())
+    }
+
+    fn is_raw_string_start(&self) -> bool {
+        let mut lookahead = self.position;
+        if self.chars.get(lookahead) == Some(&'b') {
+            lookahead += 1;
+        }
+        if self.chars.get(lookahead) != Some(&'r') {
+            return false;
+        }
+        lookahead += 1;
+        while self.chars.get(lookahead) == Some(&'#') {
+            lookahead += 1;
+        }
+        self.chars.get(lookahead) == Some(&'"')
+    }
+
+    fn copy_raw_string(&mut self, output: &mut String) -> Result<(), String> {
+        let start = self.position;
+        let open_quote = self.find_from(start, '"')?;
+        let hashes = self.chars[start..open_quote]
+            .iter()
+            .filter(|c| **c == '#')
+            .count();
+        let mut end = open_quote + 1;
+        loop {
+            end = self.find_from(end, '"')?;
+            let closing_hashes = self.chars[end + 1..]
+                .iter()
+                .take_while(|c| **c == '#')
+                .count();
+            if closing_hashes >= hashes {
+                end += hashes + 1;
+                break;
+            }
+            end += 1;
+        }
+        output.extend(&self.chars[start..end]);
+        self.position = end;
+        Ok(())
+    }
+
+    fn copy_char(&mut self, output: &mut String) -> Result<(), String> {
+        let start = self.position;
+        self.position += match self.peek(1) {
+            Some('\\') => 2,
+            _ => 1,
+        };
+        let end = self.find_from(self.position + 1, '\'')?;
+        output.extend(&self.chars[start..=end]);
+        self.position = end + 1;
+        Ok(())
+    }
+
+    // Numbers may contain letters, as in 1e5 or 0xff, which must not be read as identifiers
+    fn copy_number(&mut self, output: &mut String) {
+        let mut number = String::new();
+        while let Some(c) = self.peek(0) {
+            let is_exponent_sign = matches!(c, '+' | '-')
+                && matches!(number.chars().last(), Some('e' | 'E'))
+                && !number.starts_with("0x");
+            if !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || is_exponent_sign) {
+                break;
+            }
+            number.push(c);
+            self.position += 1;
+        }
+        output.push_str(&number);
+    }
+
+    // Copy whitespace and comments, returning whether anything follows them
+    fn skip_trivia(&mut self, output: &mut String) -> bool {
+        let start = self.position;
+        let mut end = start;
+        self.skip_trivia_from(&mut end);
+        output.extend(&self.chars[start..end]);
+        self.position = end;
+        end < self.chars.len()
+    }
+
+    fn skip_trivia_from(&self, position: &mut usize) {
+        loop {
+            match (self.chars.get(*position), self.chars.get(*position + 1)) {
+                (Some(c), _) if c.is_whitespace() => *position += 1,
+                (Some('/'), Some('/')) => {
+                    while self.chars.get(*position).is_some_and(|c| *c != '\n') {
+                        *position += 1;
+                    }
+                }
+                (Some('/'), Some('*')) => {
+                    // Block comments nest in RON
+                    let mut depth = 0;
+                    while *position < self.chars.len() {
+                        match (self.chars.get(*position), self.chars.get(*position + 1)) {
+                            (Some('/'), Some('*')) => {
+                                depth += 1;
+                                *position += 2;
+                            }
+                            (Some('*'), Some('/')) => {
+                                depth -= 1;
+                                *position += 2;
+                                if depth == 0 {
+                                    break;
+                                }
+                            }
+                            _ => *position += 1,
+                        }
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.position + offset).copied()
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        self.chars[self.position..].starts_with(&text)
+    }
+
+    fn find_from(&self, start: usize, c: char) -> Result<usize, String> {
+        self.chars[start.min(self.chars.len())..]
+            .iter()
+            .position(|other| *other == c)
+            .map(|offset| start + offset)
+            .ok_or_else(|| format!("Expected '{}' before the end of the document", c))
+    }
+
+    fn location(&self) -> String {
+        let consumed = &self.chars[..self.position];
+        let line = consumed.iter().filter(|c| **c == '\n').count() + 1;
+        let column = consumed.iter().rev().take_while(|c| **c != '\n').count() + 1;
+        format!("line {}, column {}", line, column)
+    }
+}
+
+// Whether a rewritten struct field is the "type" field of a node
+#[cfg(feature = "ron")]
+fn is_type_field(element: &str) -> bool {
+    let field = RonVariantTagger::new(element);
+    let mut start = 0;
+    field.skip_trivia_from(&mut start);
+    let name: String = field.chars[start..]
+        .iter()
+        .take_while(|c| is_identifier_char(**c) || **c == '#')
+        .collect();
+    name.trim_start_matches("r#") == "type"
+}
+
+#[cfg(feature = "ron")]
+fn is_identifier_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+#[cfg(feature = "ron")]
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(feature = "yaml")]
+pub(crate) fn parse_yaml_graph(bytes: &[u8]) -> Result<Value, GraphLoadError> {
+    serde_yaml::from_slice::<Value>(bytes).map_err(|err| GraphLoadError::Parse(Box::new(err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::graph::prepare_graph;
+
+    fn demo_graph() -> Value {
+        serde_json::from_str(include_str!("../../assets/demo/graph.midigraph.json")).unwrap()
+    }
+
+    // The document must describe the same graph as the demo's JSON, and build like it
+    fn assert_loads_as_demo_graph(path: &str, source: &str) {
+        let value = parse_graph_document(path, source.as_bytes()).unwrap();
+        assert_eq!(value, demo_graph());
+        let prepared = prepare_graph(value, &MidiGraphLoaderSettings::default()).unwrap();
+        assert_eq!(prepared.graph.node_id("music"), Some(101));
+        assert_eq!(
+            prepared.graph.midi_sources[&101].path,
+            "demo/LoopingMidi.mid"
+        );
+        assert_eq!(prepared.sub_assets.len(), 2);
+    }
+
+    #[cfg(feature = "ron")]
+    #[test]
+    fn ron_graph_loads() {
+        let source = r#"
+            (
+                type: "Midi",
+                node_id: 101,
+                name: "music",
+                source: FilePath(path: "demo/LoopingMidi.mid", track_index: 0),
+                channels: {
+                    "0": (
+                        type: "AdsrEnvelope",
+                        attack_time: 0.01,
+                        decay_time: 0.1,
+                        sustain_multiplier: 0.2,
+                        release_time: 0.1,
+                        source: (type: "LfsrNoise", inside_feedback: false),
+                    ),
+                    // Newtype variants hold their value directly
+                    "1": (
+                        type: "SampleLoop",
+                        source: FilePath("demo/guitar-a2-48k-mono.wav"),
+                        base_note: 45,
+                        looping: None,
+                    ),
+                },
+            )
+        "#;
+        assert_loads_as_demo_graph("demo/graph.midigraph.ron", source);
+    }
+
+    #[cfg(feature = "ron")]
+    #[test]
+    fn ron_variants_are_tagged_like_json() {
+        let tag = |source: &str| RonVariantTagger::new(source).tag_document().unwrap();
+        assert_eq!(tag(r#"FilePath("a.mid")"#), r#"{"FilePath": "a.mid"}"#);
+        assert_eq!(
+            tag(r#"FilePath(path: "a.mid")"#),
+            r#"{"FilePath": (path: "a.mid")}"#
+        );
+        assert_eq!(tag("Range(1, 2,)"), r#"{"Range": [1, 2]}"#);
+        assert_eq!(
+            tag("(mode: Loop, looping: Some(Loop), gain: 1e-5)"),
+            r#"(mode: "Loop", looping: Some("Loop"), gain: 1e-5)"#
+        );
+        // Strings, comments and extensions are left as written, and bare names are unit variants
+        assert_eq!(
+            tag("#![enable(implicit_some)]\n// Foo(1)\n[\"Foo(1)\", r#type]"),
+            "#![enable(implicit_some)]\n// Foo(1)\n[\"Foo(1)\", \"type\"]"
+        );
+        assert!(RonVariantTagger::new("(a: 1]").tag_document().is_err());
+    }
+
+    #[cfg(feature = "ron")]
+    #[test]
+    fn ron_node_struct_names_are_not_read_as_variants() {
+        let tag = |source: &str| RonVariantTagger::new(source).tag_document().unwrap();
+        assert_eq!(
+            tag(r#"Midi(type: "Midi", source: FilePath(path: "a.mid"))"#),
+            r#"(type: "Midi", source: {"FilePath": (path: "a.mid")})"#
+        );
+        assert_eq!(
+            tag(r#"Combiner(/* drums */ r#type: "Combiner")"#),
+            r#"(/* drums */ r#type: "Combiner")"#
+        );
+        // Variants inside options and map values are tagged like any other
+        assert_eq!(
+            tag(r#"{"0": Some(FilePath("a.mid")), "1": Some(Loop(start: 1)), "2": Some(Loop)}"#),
+            r#"{"0": Some({"FilePath": "a.mid"}), "1": Some({"Loop": (start: 1)}), "2": Some("Loop")}"#
+        );
+        let value = parse_ron_graph(
+            br#"
+            Midi(
+                type: "Midi",
+                source: FilePath(path: "a.mid", track_index: 0),
+                channels: {
+                    "0": SampleLoop(
+                        type: "SampleLoop",
+                        source: FilePath("a.wav"),
+                        looping: Some(Range(1, 2)),
+                    ),
+                    "1": Include(path: "drums.midigraph.ron", node_id_offset: 100),
+                },
+            )
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "type": "Midi",
+                "source": { "FilePath": { "path": "a.mid", "track_index": 0 } },
+                "channels": {
+                    "0": {
+                        "type": "SampleLoop",
+                        "source": { "FilePath": "a.wav" },
+                        "looping": { "Range": [1, 2] }
+                    },
+                    "1": { "Include": { "path": "drums.midigraph.ron", "node_id_offset": 100 } }
+                }
+            })
+        );
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml_graph_loads() {
+        let source = "
+type: Midi
+node_id: 101
+name: music
+source:
+  FilePath:
+    path: demo/LoopingMidi.mid
+    track_index: 0
+channels:
+  \"0\":
+    type: AdsrEnvelope
+    attack_time: 0.01
+    decay_time: 0.1
+    sustain_multiplier: 0.2
+    release_time: 0.1
+    source:
+      type: LfsrNoise
+      inside_feedback: false
+  \"1\":
+    type: SampleLoop
+    source:
+      FilePath: demo/guitar-a2-48k-mono.wav
+    base_note: 45
+    looping: null
+";
+        assert_loads_as_demo_graph("demo/graph.midigraph.yaml", source);
+    }
+}
@@ -0,0 +1,348 @@
+use crate::asset::{
+    graph::parse_graph_document,
+    names::namespace_node_names,
+    validate::{escape_pointer_token, GraphLoadError, GraphValidationError},
+};
+use bevy::asset::LoadContext;
+use serde_json::Value;
+use std::{future::Future, pin::Pin};
+
+pub(crate) type ReadFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>, String>> + Send + 'a>>;
+
+// Where included graph files are read from: the asset loader's context inside an app, or files
+// under an asset root when rendering outside of one.
+pub(crate) trait GraphReader: Send {
+    fn read<'a>(&'a mut self, path: &'a str) -> ReadFuture<'a>;
+}
+
+// Included files are read as loader dependencies, so changing one reloads every graph that
+// includes it
+impl GraphReader for LoadContext<'_> {
+    fn read<'a>(&'a mut self, path: &'a str) -> ReadFuture<'a> {
+        Box::pin(async move {
+            self.read_asset_bytes(path)
+                .await
+                .map_err(|err| err.to_string())
+        })
+    }
+}
+
+// A node written as {"Include": "path"} or {"Include": {"path": "...", "node_id_offset": 1000,
+// "namespace": "drums"}} is replaced by the root node of another graph file. The offset is added
+// to every node_id in the included graph, and node names are prefixed with the namespace, so one
+// file can be included several times without its nodes colliding.
+struct IncludeSite {
+    json_path: String,
+    path: String,
+    node_id_offset: u64,
+    namespace: Option<String>,
+}
+
+type IncludeFuture<'a> = Pin<Box<dyn Future<Output = Result<(), GraphLoadError>> + Send + 'a>>;
+
+// Replace every include in a graph document with the graph it refers to, given the path of the
+// document itself.
+pub(crate) async fn resolve_includes(
+    value: &mut Value,
+    root_path: String,
+    reader: &mut dyn GraphReader,
+) -> Result<(), GraphLoadError> {
+    let mut include_chain = vec![root_path];
+    resolve_includes_in(value, reader, &mut include_chain).await
+}
+
+fn resolve_includes_in<'a>(
+    value: &'a mut Value,
+    reader: &'a mut dyn GraphReader,
+    include_chain: &'a mut Vec<String>,
+) -> IncludeFuture<'a> {
+    Box::pin(async move {
+        let mut sites = vec![];
+        find_includes(value, String::new(), &mut sites)?;
+        for site in sites {
+            if include_chain.contains(&site.path) {
+                let chain = include_chain.join(" -> ");
+                return Err(invalid(
+                    &site.json_path,
+                    format!("Include cycle: {} -> {}", chain, site.path),
+                ));
+            }
+            let bytes = reader.read(&site.path).await.map_err(|err| {
+                invalid(
+                    &site.json_path,
+                    format!(
+                        "Cannot read graph {} included from {}: {}",
+                        site.path,
+                        include_chain.join(" -> "),
+                        err
+                    ),
+                )
+            })?;
+            let mut included = parse_graph_document(&site.path, &bytes).map_err(|err| {
+                invalid(
+                    &site.json_path,
+                    format!(
+                        "Cannot parse graph {} included from {}: {}",
+                        site.path,
+                        include_chain.join(" -> "),
+                        err
+                    ),
+                )
+            })?;
+            include_chain.push(site.path.clone());
+            resolve_includes_in(&mut included, reader, include_chain).await?;
+            include_chain.pop();
+            offset_node_ids(&mut included, site.node_id_offset).map_err(|node_id| {
+                invalid(
+                    &site.json_path,
+                    format!(
+                        "Include node_id_offset {} takes node_id {} in {} past the largest node id",
+                        site.node_id_offset, node_id, site.path
+                    ),
+                )
+            })?;
+            if let Some(namespace) = &site.namespace {
+                namespace_node_names(&mut included, namespace);
+            }
+            if let Some(target) = value.pointer_mut(&site.json_path) {
+                *target = included;
+            }
+        }
+        Ok(())
+    })
+}
+
+fn find_includes(
+    value: &Value,
+    json_path: String,
+    sites: &mut Vec<IncludeSite>,
+) -> Result<(), GraphLoadError> {
+    match value {
+        Value::Object(map) => {
+            if let Some(include) = map.get("Include") {
+                if map.len() > 1 {
+                    return Err(invalid(
+                        &json_path,
+                        "An include cannot have other fields next to it".to_owned(),
+                    ));
+                }
+                sites.push(parse_include(include, json_path)?);
+                return Ok(());
+            }
+            for (key, value) in map.iter() {
+                let json_path = format!("{}/{}", json_path, escape_pointer_token(key));
+                find_includes(value, json_path, sites)?;
+            }
+        }
+        Value::Array(values) => {
+            for (index, value) in values.iter().enumerate() {
+                find_includes(value, format!("{}/{}", json_path, index), sites)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn parse_include(include: &Value, json_path: String) -> Result<IncludeSite, GraphLoadError> {
+    let (path, node_id_offset, namespace) = match include {
+        Value::String(path) => (Some(path.as_str()), Some(0), None),
+        Value::Object(map) => (
+            map.get("path").and_then(Value::as_str),
+            match map.get("node_id_offset") {
+                None => Some(0),
+                Some(offset) => offset.as_u64(),
+            },
+            map.get("namespace").and_then(Value::as_str),
+        ),
+        _ => (None, Some(0), None),
+    };
+    let Some(path) = path else {
+        return Err(invalid(&json_path, "Include has no path".to_owned()));
+    };
+    let Some(node_id_offset) = node_id_offset else {
+        return Err(invalid(
+            &json_path,
+            "Include node_id_offset must be a whole number".to_owned(),
+        ));
+    };
+    Ok(IncludeSite {
+        json_path,
+        path: path.to_owned(),
+        node_id_offset,
+        namespace: namespace.map(str::to_owned),
+    })
+}
+
+// Fails with the first node id that the offset would take past the largest id
+fn offset_node_ids(value: &mut Value, offset: u64) -> Result<(), u64> {
+    if offset == 0 {
+        return Ok(());
+    }
+    match value {
+        Value::Object(map) => {
+            let node_id = map.get("node_id").and_then(Value::as_u64);
+            if let (true, Some(node_id)) = (map.contains_key("type"), node_id) {
+                let offset_node_id = node_id.checked_add(offset).ok_or(node_id)?;
+                map.insert("node_id".to_owned(), Value::from(offset_node_id));
+            }
+            map.values_mut()
+                .try_for_each(|value| offset_node_ids(value, offset))
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .try_for_each(|value| offset_node_ids(value, offset)),
+        _ => Ok(()),
+    }
+}
+
+fn invalid(json_path: &str, message: String) -> GraphLoadError {
+    GraphLoadError::Invalid(vec![GraphValidationError {
+        json_path: json_path.to_owned(),
+        message,
+    }])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    struct MemoryReader(HashMap<&'static str, Value>);
+
+    impl GraphReader for MemoryReader {
+        fn read<'a>(&'a mut self, path: &'a str) -> ReadFuture<'a> {
+            let bytes = self
+                .0
+                .get(path)
+                .map(|value| value.to_string().into_bytes())
+                .ok_or_else(|| format!("No file at {}", path));
+            Box::pin(async move { bytes })
+        }
+    }
+
+    fn resolve(mut root: Value, files: &[(&'static str, Value)]) -> Result<Value, GraphLoadError> {
+        let mut reader = MemoryReader(files.iter().cloned().collect());
+        bevy::tasks::block_on(resolve_includes(
+            &mut root,
+            "root.json".to_owned(),
+            &mut reader,
+        ))?;
+        Ok(root)
+    }
+
+    fn error_message(result: Result<Value, GraphLoadError>) -> (String, String) {
+        match result {
+            Err(GraphLoadError::Invalid(errors)) => {
+                (errors[0].json_path.clone(), errors[0].message.clone())
+            }
+            other => panic!("Expected an invalid graph, got {:?}", other),
+        }
+    }
+
+    fn drum_graph() -> Value {
+        json!({ "type": "SquareWaveSource", "node_id": 1, "name": "kick" })
+    }
+
+    #[test]
+    fn includes_are_replaced_by_the_included_graph() {
+        let root = json!({
+            "type": "Combiner",
+            "sources": [
+                { "Include": "drums.json" },
+                { "Include": { "path": "drums.json", "node_id_offset": 100, "namespace": "fill" } }
+            ]
+        });
+        let resolved = resolve(root, &[("drums.json", drum_graph())]).unwrap();
+        assert_eq!(
+            resolved,
+            json!({
+                "type": "Combiner",
+                "sources": [
+                    { "type": "SquareWaveSource", "node_id": 1, "name": "kick" },
+                    { "type": "SquareWaveSource", "node_id": 101, "name": "fill.kick" }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn offsets_of_nested_includes_add_up() {
+        let root = json!({ "Include": { "path": "kit.json", "node_id_offset": 1000 } });
+        let kit = json!({
+            "type": "Combiner",
+            "node_id": 5,
+            "sources": [{ "Include": { "path": "drums.json", "node_id_offset": 10 } }]
+        });
+        let resolved = resolve(root, &[("kit.json", kit), ("drums.json", drum_graph())]).unwrap();
+        assert_eq!(resolved["node_id"], 1005);
+        assert_eq!(resolved["sources"][0]["node_id"], 1011);
+    }
+
+    #[test]
+    fn offsets_only_apply_to_nodes() {
+        let mut value = json!({
+            "type": "Midi",
+            "node_id": 1,
+            "events": [{ "node_id": 2 }]
+        });
+        offset_node_ids(&mut value, 10).unwrap();
+        assert_eq!(value["node_id"], 11);
+        assert_eq!(value["events"][0]["node_id"], 2);
+    }
+
+    #[test]
+    fn include_cycles_are_reported() {
+        let root = json!({ "Include": "a.json" });
+        let a = json!({ "type": "Combiner", "sources": [{ "Include": "b.json" }] });
+        let b = json!({ "Include": "a.json" });
+        let (json_path, message) = error_message(resolve(root, &[("a.json", a), ("b.json", b)]));
+        assert_eq!(json_path, "");
+        assert_eq!(
+            message,
+            "Include cycle: root.json -> a.json -> b.json -> a.json"
+        );
+        let (_, message) = error_message(resolve(json!({ "Include": "root.json" }), &[]));
+        assert_eq!(message, "Include cycle: root.json -> root.json");
+    }
+
+    #[test]
+    fn including_the_same_file_twice_is_not_a_cycle() {
+        let root = json!([{ "Include": "drums.json" }, { "Include": "drums.json" }]);
+        assert!(resolve(root, &[("drums.json", drum_graph())]).is_ok());
+    }
+
+    #[test]
+    fn bad_includes_are_reported_where_they_are() {
+        let root = json!({ "type": "Combiner", "sources": [{ "Include": "missing.json" }] });
+        let (json_path, message) = error_message(resolve(root, &[]));
+        assert_eq!(json_path, "/sources/0");
+        assert!(message.starts_with("Cannot read graph missing.json included from root.json"));
+
+        let root = json!({ "sources": [{ "Include": "drums.json", "node_id": 1 }] });
+        let (json_path, _) = error_message(resolve(root, &[]));
+        assert_eq!(json_path, "/sources/0");
+
+        let root = json!({ "Include": { "path": "drums.json", "node_id_offset": -1 } });
+        let (_, message) = error_message(resolve(root, &[]));
+        assert_eq!(message, "Include node_id_offset must be a whole number");
+    }
+
+    #[test]
+    fn offsets_past_the_largest_node_id_are_reported() {
+        let root = json!({
+            "type": "Combiner",
+            "sources": [{ "Include": { "path": "drums.json", "node_id_offset": u64::MAX } }]
+        });
+        let (json_path, message) = error_message(resolve(root, &[("drums.json", drum_graph())]));
+        assert_eq!(json_path, "/sources/0");
+        assert_eq!(
+            message,
+            format!(
+                "Include node_id_offset {} takes node_id 1 in drums.json past the largest node id",
+                u64::MAX
+            )
+        );
+    }
+}
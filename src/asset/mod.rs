@@ -1,9 +1,14 @@
 use std::fmt::Display;
 
 pub(crate) mod graph;
+#[cfg(any(feature = "ron", feature = "yaml"))]
+pub(crate) mod graph_formats;
+pub(crate) mod include;
 pub(crate) mod loader;
 pub(crate) mod midi;
+pub(crate) mod names;
 pub(crate) mod sf2;
+pub(crate) mod validate;
 pub(crate) mod wave;
 
 #[derive(Debug)]
@@ -0,0 +1,241 @@
+use crate::asset::validate::{escape_pointer_token, GraphLoadError, GraphValidationError};
+use serde_json::Value;
+use std::collections::HashMap;
+
+// Nodes can be given a "name" for game code to refer to them by. Named nodes without a
+// node_id are given one above every explicit id, in document order, and names are removed
+// before the graph is built since node configs don't know about them.
+pub(crate) fn assign_named_nodes(
+    value: &mut Value,
+) -> Result<HashMap<String, u64>, GraphLoadError> {
+    let mut named_nodes = vec![];
+    let mut max_node_id = 0;
+    collect_named_nodes(value, String::new(), &mut named_nodes, &mut max_node_id);
+
+    let mut node_names = HashMap::new();
+    let mut first_paths: HashMap<String, String> = HashMap::new();
+    let mut errors = vec![];
+    let mut next_node_id = max_node_id + 1;
+    for (json_path, name) in named_nodes {
+        if let Some(first_path) = first_paths.get(&name) {
+            errors.push(GraphValidationError {
+                json_path: format!("{}/name", json_path),
+                message: format!(
+                    "Duplicate node name \"{}\", already used at {}",
+                    name, first_path
+                ),
+            });
+            continue;
+        }
+        let Some(node) = value.pointer_mut(&json_path).and_then(Value::as_object_mut) else {
+            continue;
+        };
+        node.remove("name");
+        let node_id = match node.get("node_id").and_then(Value::as_u64) {
+            Some(node_id) => node_id,
+            None => {
+                let node_id = next_node_id;
+                next_node_id += 1;
+                node.insert("node_id".to_owned(), Value::from(node_id));
+                node_id
+            }
+        };
+        first_paths.insert(name.clone(), json_path);
+        node_names.insert(name, node_id);
+    }
+    match errors.is_empty() {
+        true => Ok(node_names),
+        false => Err(GraphLoadError::Invalid(errors)),
+    }
+}
+
+// Midi nodes are followed by node id, for the MusicClock, scheduling and playback notifications,
+// so those without a node_id are given one above every other id, in document order. Run after
+// assign_named_nodes, so that named Midi nodes keep the ids their names were given.
+pub(crate) fn assign_midi_node_ids(value: &mut Value) {
+    let mut next_node_id = max_node_id(value) + 1;
+    assign_missing_midi_ids(value, &mut next_node_id);
+}
+
+fn max_node_id(value: &Value) -> u64 {
+    match value {
+        Value::Object(map) => {
+            let node_id = match map.contains_key("type") {
+                true => map.get("node_id").and_then(Value::as_u64).unwrap_or(0),
+                false => 0,
+            };
+            map.values().map(max_node_id).fold(node_id, u64::max)
+        }
+        Value::Array(values) => values.iter().map(max_node_id).fold(0, u64::max),
+        _ => 0,
+    }
+}
+
+fn assign_missing_midi_ids(value: &mut Value, next_node_id: &mut u64) {
+    match value {
+        Value::Object(map) => {
+            let is_midi = map.get("type").and_then(Value::as_str) == Some("Midi");
+            if is_midi && !map.contains_key("node_id") {
+                map.insert("node_id".to_owned(), Value::from(*next_node_id));
+                *next_node_id += 1;
+            }
+            map.values_mut()
+                .for_each(|value| assign_missing_midi_ids(value, next_node_id));
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| assign_missing_midi_ids(value, next_node_id)),
+        _ => {}
+    }
+}
+
+fn collect_named_nodes(
+    value: &Value,
+    json_path: String,
+    named_nodes: &mut Vec<(String, String)>,
+    max_node_id: &mut u64,
+) {
+    match value {
+        Value::Object(map) => {
+            if map.contains_key("type") {
+                if let Some(node_id) = map.get("node_id").and_then(Value::as_u64) {
+                    *max_node_id = (*max_node_id).max(node_id);
+                }
+                if let Some(name) = map.get("name").and_then(Value::as_str) {
+                    named_nodes.push((json_path.clone(), name.to_owned()));
+                }
+            }
+            for (key, value) in map.iter() {
+                let json_path = format!("{}/{}", json_path, escape_pointer_token(key));
+                collect_named_nodes(value, json_path, named_nodes, max_node_id);
+            }
+        }
+        Value::Array(values) => {
+            for (index, value) in values.iter().enumerate() {
+                let json_path = format!("{}/{}", json_path, index);
+                collect_named_nodes(value, json_path, named_nodes, max_node_id);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Prefix the names of nodes in an included graph, so the same file can be included more than
+// once with its nodes still told apart
+pub(crate) fn namespace_node_names(value: &mut Value, namespace: &str) {
+    match value {
+        Value::Object(map) => {
+            let name = map.get("name").and_then(Value::as_str);
+            if let (true, Some(name)) = (map.contains_key("type"), name) {
+                let name = format!("{}.{}", namespace, name);
+                map.insert("name".to_owned(), Value::from(name));
+            }
+            map.values_mut()
+                .for_each(|value| namespace_node_names(value, namespace));
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| namespace_node_names(value, namespace)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn named_nodes_get_ids_above_every_explicit_id() {
+        let mut graph = json!({
+            "type": "Combiner",
+            "name": "mix",
+            "sources": [
+                { "type": "SquareWaveSource", "node_id": 40, "name": "lead" },
+                { "type": "SawtoothWaveSource", "name": "bass" },
+                { "type": "Midi", "node_id": 7 }
+            ]
+        });
+        let node_names = assign_named_nodes(&mut graph).unwrap();
+        assert_eq!(node_names["lead"], 40);
+        assert_eq!(node_names["mix"], 41);
+        assert_eq!(node_names["bass"], 42);
+        assert_eq!(node_names.len(), 3);
+        // Names are gone from the document, and generated ids are written into it
+        assert_eq!(
+            graph,
+            json!({
+                "type": "Combiner",
+                "node_id": 41,
+                "sources": [
+                    { "type": "SquareWaveSource", "node_id": 40 },
+                    { "type": "SawtoothWaveSource", "node_id": 42 },
+                    { "type": "Midi", "node_id": 7 }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn midi_nodes_without_ids_are_given_one() {
+        let mut graph = json!({
+            "type": "Combiner",
+            "node_id": 3,
+            "sources": [
+                { "type": "Midi", "node_id": 9 },
+                { "type": "Midi", "name": "theme" },
+                { "type": "Midi" },
+                { "type": "SquareWaveSource" }
+            ]
+        });
+        let node_names = assign_named_nodes(&mut graph).unwrap();
+        assign_midi_node_ids(&mut graph);
+        assert_eq!(node_names["theme"], 10);
+        assert_eq!(graph["sources"][0]["node_id"], 9);
+        assert_eq!(graph["sources"][2]["node_id"], 11);
+        // Only Midi nodes need an id to be followed
+        assert!(graph["sources"][3].get("node_id").is_none());
+    }
+
+    #[test]
+    fn names_outside_nodes_are_left_alone() {
+        let mut graph = json!({
+            "type": "Midi",
+            "source": { "FilePath": { "path": "music.mid", "name": "not a node" } }
+        });
+        let node_names = assign_named_nodes(&mut graph).unwrap();
+        assert!(node_names.is_empty());
+        assert_eq!(graph["source"]["FilePath"]["name"], "not a node");
+    }
+
+    #[test]
+    fn duplicate_names_are_reported() {
+        let mut graph = json!({
+            "type": "Combiner",
+            "sources": [
+                { "type": "SquareWaveSource", "name": "lead" },
+                { "type": "SawtoothWaveSource", "name": "lead" }
+            ]
+        });
+        let Err(GraphLoadError::Invalid(errors)) = assign_named_nodes(&mut graph) else {
+            panic!("Expected duplicate names to be invalid");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].json_path, "/sources/1/name");
+        assert!(errors[0].message.contains("/sources/0"));
+    }
+
+    #[test]
+    fn namespaces_prefix_node_names() {
+        let mut graph = json!({
+            "type": "Combiner",
+            "name": "kit",
+            "sources": [{ "type": "SquareWaveSource", "name": "kick" }]
+        });
+        namespace_node_names(&mut graph, "drums");
+        let node_names = assign_named_nodes(&mut graph).unwrap();
+        let mut names: Vec<&str> = node_names.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, vec!["drums.kick", "drums.kit"]);
+    }
+}
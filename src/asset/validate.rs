@@ -0,0 +1,371 @@
+use crate::GraphAssetLoader;
+use serde_json::Value;
+use std::{collections::HashMap, fmt::Display};
+
+const MIDI_CHANNEL_COUNT: u64 = 16;
+
+/// A problem found in a graph file, at a JSON pointer such as `/channels/0/source`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphValidationError {
+    pub json_path: String,
+    pub message: String,
+}
+
+impl Display for GraphValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let json_path = match self.json_path.as_str() {
+            "" => "/",
+            json_path => json_path,
+        };
+        write!(f, "{}: {}", json_path, self.message)
+    }
+}
+
+/// Why a graph file could not be loaded.
+#[derive(Debug)]
+pub enum GraphLoadError {
+    Read(std::io::Error),
+    /// The file could not be parsed in its format, or does not describe a graph.
+    Parse(Box<dyn std::error::Error + Send + Sync>),
+    /// The graph is well-formed but could not be built as it is.
+    Invalid(Vec<GraphValidationError>),
+}
+
+impl Display for GraphLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphLoadError::Read(err) => write!(f, "Cannot read graph: {}", err),
+            GraphLoadError::Parse(err) => write!(f, "Cannot parse graph: {}", err),
+            GraphLoadError::Invalid(errors) => {
+                write!(f, "Invalid graph:")?;
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for GraphLoadError {}
+
+impl From<std::io::Error> for GraphLoadError {
+    fn from(err: std::io::Error) -> Self {
+        GraphLoadError::Read(err)
+    }
+}
+
+impl From<serde_json::Error> for GraphLoadError {
+    fn from(err: serde_json::Error) -> Self {
+        GraphLoadError::Parse(Box::new(err))
+    }
+}
+
+// Check a graph's JSON for mistakes that would otherwise only show up when its nodes are built,
+// reporting all of them at once.
+pub fn validate_graph(value: &Value) -> Result<(), GraphLoadError> {
+    let mut validator = GraphValidator::default();
+    validator.visit(value, String::new());
+    match validator.errors.is_empty() {
+        true => Ok(()),
+        false => Err(GraphLoadError::Invalid(validator.errors)),
+    }
+}
+
+#[derive(Default)]
+struct GraphValidator {
+    node_id_paths: HashMap<u64, String>,
+    errors: Vec<GraphValidationError>,
+}
+
+impl GraphValidator {
+    fn visit(&mut self, value: &Value, json_path: String) {
+        match value {
+            Value::Object(map) => {
+                if map.contains_key("type") {
+                    self.check_node(map, &json_path);
+                }
+                if let Some(file_source) = map.get("FilePath") {
+                    self.check_file_source(file_source, &format!("{}/FilePath", json_path));
+                }
+                self.check_range(map, &json_path);
+                for (key, value) in map.iter() {
+                    self.visit(
+                        value,
+                        format!("{}/{}", json_path, escape_pointer_token(key)),
+                    );
+                }
+            }
+            Value::Array(values) => {
+                for (index, value) in values.iter().enumerate() {
+                    self.visit(value, format!("{}/{}", json_path, index));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn error(&mut self, json_path: &str, message: String) {
+        self.errors.push(GraphValidationError {
+            json_path: json_path.to_owned(),
+            message,
+        });
+    }
+
+    fn check_node(&mut self, node: &serde_json::Map<String, Value>, json_path: &str) {
+        if let Some(node_id) = node.get("node_id").and_then(Value::as_u64) {
+            if let Some(first_path) = self.node_id_paths.get(&node_id) {
+                let message = format!(
+                    "Duplicate node_id {}, already used at {}",
+                    node_id, first_path
+                );
+                self.error(&format!("{}/node_id", json_path), message);
+            } else {
+                self.node_id_paths.insert(node_id, json_path.to_owned());
+            }
+        }
+        if node.get("type").and_then(Value::as_str) == Some("Midi") {
+            self.check_midi_channels(node.get("channels"), json_path);
+        }
+        for (key, value) in node.iter() {
+            let is_time = key.ends_with("_time");
+            if let (true, Some(time)) = (is_time, value.as_f64())
+                && time < 0.0
+            {
+                let message = format!("{} cannot be negative, got {}", key, time);
+                self.error(&format!("{}/{}", json_path, key), message);
+            }
+        }
+    }
+
+    fn check_midi_channels(&mut self, channels: Option<&Value>, json_path: &str) {
+        let channels_path = format!("{}/channels", json_path);
+        let Some(channels) = channels.and_then(Value::as_object) else {
+            self.error(json_path, "Midi node has no channels".to_owned());
+            return;
+        };
+        if channels.is_empty() {
+            self.error(&channels_path, "Midi node has no channels".to_owned());
+        }
+        for key in channels.keys() {
+            match key.parse::<u64>() {
+                Ok(channel) if channel < MIDI_CHANNEL_COUNT => {}
+                _ => self.error(
+                    &format!("{}/{}", channels_path, escape_pointer_token(key)),
+                    format!(
+                        "MIDI channel must be a number from 0 to {}, got \"{}\"",
+                        MIDI_CHANNEL_COUNT - 1,
+                        key
+                    ),
+                ),
+            }
+        }
+    }
+
+    // File sources are either a path or an object with a path, such as for MIDI tracks
+    fn check_file_source(&mut self, file_source: &Value, json_path: &str) {
+        let (path, path_json_path) = match file_source {
+            Value::String(path) => (Some(path.as_str()), json_path.to_owned()),
+            Value::Object(map) => (
+                map.get("path").and_then(Value::as_str),
+                format!("{}/path", json_path),
+            ),
+            _ => (None, json_path.to_owned()),
+        };
+        let Some(path) = path else {
+            self.error(json_path, "File source has no path".to_owned());
+            return;
+        };
+        if let Err(err) = GraphAssetLoader::infer_asset_type(path) {
+            self.error(&path_json_path, format!("{:?}", err));
+        }
+    }
+
+    // Any pair of bounds, such as a note range or loop range, must not be reversed
+    fn check_range(&mut self, map: &serde_json::Map<String, Value>, json_path: &str) {
+        for (lower_key, upper_key) in [("lower", "upper"), ("start", "end")] {
+            let lower = map.get(lower_key).and_then(Value::as_f64);
+            let upper = map.get(upper_key).and_then(Value::as_f64);
+            if let (Some(lower), Some(upper)) = (lower, upper)
+                && lower > upper
+            {
+                let message = format!(
+                    "Range {} {} is after {} {}",
+                    lower_key, lower, upper_key, upper
+                );
+                self.error(json_path, message);
+            }
+        }
+    }
+}
+
+// JSON pointer of the path given to a file source, for reporting problems found with it after
+// validation
+pub(crate) fn find_file_source_path(value: &Value, path: &str) -> Option<String> {
+    match value {
+        Value::Object(map) => {
+            let file_source_path = match map.get("FilePath") {
+                Some(Value::String(file_path)) if file_path == path => Some("/FilePath"),
+                Some(Value::Object(file_source))
+                    if file_source.get("path").and_then(Value::as_str) == Some(path) =>
+                {
+                    Some("/FilePath/path")
+                }
+                _ => None,
+            };
+            if let Some(file_source_path) = file_source_path {
+                return Some(file_source_path.to_owned());
+            }
+            map.iter().find_map(|(key, value)| {
+                let json_path = find_file_source_path(value, path)?;
+                Some(format!("/{}{}", escape_pointer_token(key), json_path))
+            })
+        }
+        Value::Array(values) => values.iter().enumerate().find_map(|(index, value)| {
+            let json_path = find_file_source_path(value, path)?;
+            Some(format!("/{}{}", index, json_path))
+        }),
+        _ => None,
+    }
+}
+
+// JSON pointer escaping, so keys containing '/' or '~' still give usable paths
+pub(crate) fn escape_pointer_token(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn validation_errors(value: Value) -> Vec<GraphValidationError> {
+        match validate_graph(&value) {
+            Ok(()) => vec![],
+            Err(GraphLoadError::Invalid(errors)) => errors,
+            Err(err) => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    fn json_paths(errors: &[GraphValidationError]) -> Vec<&str> {
+        errors
+            .iter()
+            .map(|error| error.json_path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn valid_graph_passes() {
+        let graph = json!({
+            "type": "Midi",
+            "node_id": 1,
+            "source": { "FilePath": { "path": "music.mid", "track_index": 0 } },
+            "channels": {
+                "0": {
+                    "type": "SampleLoop",
+                    "node_id": 2,
+                    "source": { "FilePath": "guitar.wav" }
+                }
+            }
+        });
+        assert_eq!(validation_errors(graph), vec![]);
+    }
+
+    #[test]
+    fn duplicate_node_ids_point_at_both_nodes() {
+        let graph = json!({
+            "type": "Mixer",
+            "sources": [
+                { "type": "SquareWaveSource", "node_id": 7 },
+                { "type": "SawtoothWaveSource", "node_id": 7 }
+            ]
+        });
+        let errors = validation_errors(graph);
+        assert_eq!(json_paths(&errors), vec!["/sources/1/node_id"]);
+        assert!(errors[0].message.contains("/sources/0"));
+    }
+
+    #[test]
+    fn midi_channels_must_be_in_range() {
+        let graph = json!({
+            "type": "Midi",
+            "source": { "FilePath": "music.mid" },
+            "channels": {
+                "15": { "type": "SquareWaveSource" },
+                "16": { "type": "SquareWaveSource" },
+                "drums": { "type": "SquareWaveSource" }
+            }
+        });
+        let errors = validation_errors(graph);
+        assert_eq!(json_paths(&errors), vec!["/channels/16", "/channels/drums"]);
+        let graph = json!({ "type": "Midi", "source": { "FilePath": "music.mid" } });
+        assert_eq!(json_paths(&validation_errors(graph)), vec![""]);
+    }
+
+    #[test]
+    fn reversed_ranges_and_negative_times_are_reported() {
+        let graph = json!({
+            "type": "Combiner",
+            "sources": [
+                { "type": "Filter", "range": { "lower": 60, "upper": 48 } },
+                { "type": "SampleLoop", "looping": { "start": 100, "end": 100 } },
+                { "type": "AdsrEnvelope", "attack_time": -0.5 }
+            ]
+        });
+        let errors = validation_errors(graph);
+        assert_eq!(
+            json_paths(&errors),
+            vec!["/sources/0/range", "/sources/2/attack_time"]
+        );
+    }
+
+    #[test]
+    fn unknown_file_types_point_at_the_path() {
+        let graph = json!({
+            "type": "Combiner",
+            "sources": [
+                { "type": "SampleLoop", "source": { "FilePath": "guitar.mp3" } },
+                { "type": "Midi", "source": { "FilePath": { "path": "music" } }, "channels": {} }
+            ]
+        });
+        let errors = validation_errors(graph);
+        assert_eq!(
+            json_paths(&errors),
+            vec![
+                "/sources/0/source/FilePath",
+                "/sources/1/channels",
+                "/sources/1/source/FilePath/path",
+            ]
+        );
+    }
+
+    #[test]
+    fn file_source_paths_are_found_by_path() {
+        let graph = json!({
+            "type": "Combiner",
+            "sources": [
+                { "type": "SampleLoop", "source": { "FilePath": "guitar.wav" } },
+                { "type": "Midi", "source": { "FilePath": { "path": "a/b.mid" } } }
+            ]
+        });
+        assert_eq!(
+            find_file_source_path(&graph, "guitar.wav").as_deref(),
+            Some("/sources/0/source/FilePath")
+        );
+        assert_eq!(
+            find_file_source_path(&graph, "a/b.mid").as_deref(),
+            Some("/sources/1/source/FilePath/path")
+        );
+        assert_eq!(find_file_source_path(&graph, "missing.wav"), None);
+    }
+
+    #[test]
+    fn json_paths_escape_keys_and_show_the_root() {
+        assert_eq!(escape_pointer_token("a/b~c"), "a~1b~0c");
+        let error = GraphValidationError {
+            json_path: String::new(),
+            message: "Problem".to_owned(),
+        };
+        assert_eq!(error.to_string(), "/: Problem");
+    }
+}
@@ -0,0 +1,602 @@
+use bevy::prelude::*;
+use crossbeam_channel::Receiver;
+use midi_graph::{Error, GraphNode, Message, MessageSender, Node};
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
+
+// midi-graph nodes render interleaved stereo at a fixed rate, so offline output always uses
+// these rather than being configurable
+pub const SAMPLE_RATE: u32 = 48000;
+pub const CHANNEL_COUNT: usize = 2;
+
+// Frames mixed at a time when the mix is played back at a different rate
+const RESAMPLE_CHUNK_FRAMES: usize = 64;
+// Frames on each side of an output frame that the resampling filter reads. More taps give a
+// steeper filter, keeping more of the highs while still removing what would alias.
+const SINC_HALF_TAPS: usize = 32;
+// Positions between two frames that filter weights are computed for
+const SINC_PHASES: usize = 256;
+const FAST_FORWARD_CHUNK_FRAMES: usize = 4096;
+
+/// Where the audio produced by the plugin's mixer ends up.
+#[derive(Clone, Debug, Default)]
+pub enum AudioBackend {
+    /// Play through the system's default output device. When the device can't be opened, the
+    /// plugin logs a warning and falls back to [`AudioBackend::Null`].
+    #[default]
+    Device,
+    /// Discard all audio. Programs are still built and played as real time passes, so the same
+    /// app, including its [`MusicClock`](crate::MusicClock), runs on machines without a sound
+    /// device.
+    Null,
+    /// Render audio into [`OfflineAudioOutput`] as the Bevy schedule runs, without any device.
+    Offline(OfflineConfig),
+}
+
+/// Whether music follows the game's [`Time<Virtual>`]. Inserted as a resource by the plugin, so
+/// it can be changed while the app runs.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq)]
+pub enum VirtualTimeSync {
+    /// Music plays in real time, whatever virtual time is doing.
+    #[default]
+    Off,
+    /// Music pauses while virtual time is paused.
+    Pause,
+    /// Music pauses with virtual time, and its playback rate follows virtual time's relative
+    /// speed.
+    PauseAndSpeed,
+}
+
+impl VirtualTimeSync {
+    pub(crate) fn playback_rate(&self, time: &Time<Virtual>) -> f32 {
+        match self {
+            VirtualTimeSync::Off => 1.0,
+            _ if time.is_paused() => 0.0,
+            VirtualTimeSync::Pause => 1.0,
+            VirtualTimeSync::PauseAndSpeed => time.relative_speed(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OfflineConfig {
+    /// Frames rendered on every update. When `None`, the number of frames follows the time
+    /// elapsed since the previous update.
+    pub frames_per_update: Option<usize>,
+}
+
+/// Interleaved samples rendered by the [`AudioBackend::Offline`] backend, at
+/// [`OfflineAudioOutput::SAMPLE_RATE`] with [`OfflineAudioOutput::CHANNEL_COUNT`] channels.
+#[derive(Resource)]
+pub struct OfflineAudioOutput {
+    pub(crate) frames_per_update: Option<usize>,
+    samples: Vec<f32>,
+}
+
+impl OfflineAudioOutput {
+    pub const SAMPLE_RATE: u32 = SAMPLE_RATE;
+    pub const CHANNEL_COUNT: usize = CHANNEL_COUNT;
+
+    pub fn new(config: &OfflineConfig) -> Self {
+        Self {
+            frames_per_update: config.frames_per_update,
+            samples: vec![],
+        }
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.samples.len() / CHANNEL_COUNT
+    }
+
+    // Remove and return everything rendered so far.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub(crate) fn append(&mut self, samples: &[f32]) {
+        self.samples.extend_from_slice(samples);
+    }
+}
+
+// A program playing in the mixer, and the gain applied to its output. The gain is applied to the
+// mixed audio rather than sent to the program's nodes, so it never overwrites volumes set on them.
+#[derive(Clone, Copy, Debug)]
+struct Deck {
+    program_no: usize,
+    gain: f32,
+}
+
+// The decks that gains can be set on. A crossfade plays the previous program on a second deck
+// until it completes, and only while it runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeckSlot {
+    Active,
+    Previous,
+}
+
+// A graph played for a player entity alongside the programs. It has its own event channel, so
+// events sent to it never reach the programs or other players, and its own gain and pan, which
+// are applied to its output like deck gains.
+struct PlayerVoice {
+    node: GraphNode,
+    event_sender: Arc<MessageSender>,
+    event_receiver: Receiver<Message>,
+    gain: f32,
+    pan: f32,
+}
+
+impl PlayerVoice {
+    // Balance-style pan from -1 (left) to 1 (right), which attenuates the far channel and leaves
+    // the near one as it is
+    fn channel_gains(&self) -> [f32; CHANNEL_COUNT] {
+        [
+            self.gain * (1.0 - self.pan).min(1.0),
+            self.gain * (1.0 + self.pan).min(1.0),
+        ]
+    }
+}
+
+// Mixes the playing programs and players into one interleaved stereo output. The same mixer is
+// used by every backend: with the device backend, the mixer thread renders it ahead of an output
+// stream, while the other backends render it on demand.
+//
+// This takes the place of midi-graph's BaseMixer, which opens an output stream of its own and
+// plays a single program with no gain stage. Crossfading two programs through BaseMixer took a
+// second mixer, so a second device stream and a second build of every program, and deck gains
+// had to be broadcast as Volume events that overwrote the volume of every node in the program.
+// Each player needed yet another stream. Mixing here builds each program once, applies deck and
+// player gains to the mixed audio, and leaves one stream for the crate to open, which is also
+// where the mix is resampled to the device's rate and to the playback rate that follows virtual
+// time. The nodes themselves are still midi-graph's, rendered through Node::fill_buffer.
+pub struct Mixer {
+    programs: HashMap<usize, GraphNode>,
+    active_deck: Option<Deck>,
+    previous_deck: Option<Deck>,
+    players: HashMap<Entity, PlayerVoice>,
+    event_sender: Arc<MessageSender>,
+    event_receiver: Receiver<Message>,
+    playback_rate: f32,
+    resampler: Resampler,
+    deck_buffer: Vec<f32>,
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        let (event_sender, event_receiver) = crossbeam_channel::unbounded();
+        Self {
+            programs: HashMap::new(),
+            active_deck: None,
+            previous_deck: None,
+            players: HashMap::new(),
+            event_sender: Arc::new(event_sender),
+            event_receiver,
+            playback_rate: 1.0,
+            resampler: Resampler::default(),
+            deck_buffer: vec![],
+        }
+    }
+
+    // Returns the program that was already stored at the given program number, if any, so that
+    // the caller can drop it away from the mixer thread.
+    pub fn store_program(&mut self, program_no: usize, node: GraphNode) -> Option<GraphNode> {
+        self.programs.insert(program_no, node)
+    }
+
+    // Change the program playing on the active deck, keeping the deck's gain.
+    pub fn change_program(&mut self, program_no: usize) -> Result<(), Error> {
+        self.check_stored(program_no)?;
+        let gain = self.active_deck.map_or(1.0, |deck| deck.gain);
+        self.active_deck = Some(Deck { program_no, gain });
+        // A program only plays on one deck at a time
+        if self.previous_deck.map(|deck| deck.program_no) == Some(program_no) {
+            self.previous_deck = None;
+        }
+        Ok(())
+    }
+
+    // Start a program silently on the active deck, keeping the program that was playing on the
+    // previous deck so that the two can be crossfaded.
+    pub fn switch_deck(&mut self, program_no: usize) -> Result<(), Error> {
+        self.check_stored(program_no)?;
+        self.previous_deck = self
+            .active_deck
+            .take()
+            .filter(|deck| deck.program_no != program_no);
+        self.active_deck = Some(Deck {
+            program_no,
+            gain: 0.0,
+        });
+        Ok(())
+    }
+
+    // Stop the program on the previous deck, once a crossfade has finished with it.
+    pub fn stop_previous_deck(&mut self) {
+        self.previous_deck = None;
+    }
+
+    pub fn set_deck_gain(&mut self, slot: DeckSlot, gain: f32) {
+        let deck = match slot {
+            DeckSlot::Active => self.active_deck.as_mut(),
+            DeckSlot::Previous => self.previous_deck.as_mut(),
+        };
+        if let Some(deck) = deck {
+            deck.gain = gain;
+        }
+    }
+
+    fn check_stored(&self, program_no: usize) -> Result<(), Error> {
+        match self.programs.contains_key(&program_no) {
+            true => Ok(()),
+            false => Err(Error::User(format!(
+                "Program {} has not been stored",
+                program_no
+            ))),
+        }
+    }
+
+    // Start playing a graph for a player entity, replacing any it was already playing. Returns
+    // the sender for events to the player's graph.
+    pub fn add_player(&mut self, entity: Entity, node: GraphNode) -> Arc<MessageSender> {
+        let (event_sender, event_receiver) = crossbeam_channel::unbounded();
+        let event_sender = Arc::new(event_sender);
+        self.players.insert(
+            entity,
+            PlayerVoice {
+                node,
+                event_sender: event_sender.clone(),
+                event_receiver,
+                gain: 1.0,
+                pan: 0.0,
+            },
+        );
+        event_sender
+    }
+
+    // Returns the player's graph, so that the caller can drop it away from the mixer thread.
+    pub fn remove_player(&mut self, entity: Entity) -> Option<GraphNode> {
+        self.players.remove(&entity).map(|player| player.node)
+    }
+
+    pub fn set_player_gain_and_pan(&mut self, entity: Entity, gain: f32, pan: f32) {
+        if let Some(player) = self.players.get_mut(&entity) {
+            player.gain = gain.max(0.0);
+            player.pan = pan.clamp(-1.0, 1.0);
+        }
+    }
+
+    pub fn get_player_node_state_snapshot(
+        &mut self,
+        entity: Entity,
+        node_id: u64,
+    ) -> Option<Result<Value, Error>> {
+        self.process_events();
+        self.players
+            .get_mut(&entity)?
+            .node
+            .get_node_state_snapshot(node_id)
+    }
+
+    // Events are delivered to the program on the active deck.
+    pub fn get_event_sender(&self) -> Arc<MessageSender> {
+        self.event_sender.clone()
+    }
+
+    pub fn get_active_node_state_snapshot(&mut self, node_id: u64) -> Option<Result<Value, Error>> {
+        self.process_events();
+        let program_no = self.active_deck?.program_no;
+        self.programs
+            .get_mut(&program_no)?
+            .get_node_state_snapshot(node_id)
+    }
+
+    // Play everything faster or slower, with zero pausing playback without advancing it.
+    pub fn set_playback_rate(&mut self, rate: f32) {
+        self.playback_rate = rate.max(0.0);
+    }
+
+    fn process_events(&mut self) {
+        let mut node = self
+            .active_deck
+            .and_then(|deck| self.programs.get_mut(&deck.program_no));
+        for message in self.event_receiver.try_iter() {
+            if let Some(node) = node.as_mut() {
+                node.on_event(&message);
+            }
+        }
+        for player in self.players.values_mut() {
+            for message in player.event_receiver.try_iter() {
+                player.node.on_event(&message);
+            }
+        }
+    }
+
+    // Fill an interleaved stereo buffer at the mixer's own sample rate.
+    pub fn render(&mut self, buffer: &mut [f32]) {
+        self.render_at_rate(buffer, SAMPLE_RATE);
+    }
+
+    // Fill an interleaved stereo buffer for output at the given sample rate, following the
+    // playback rate.
+    pub fn render_at_rate(&mut self, buffer: &mut [f32], output_rate: u32) {
+        self.process_events();
+        let step = self.playback_rate as f64 * SAMPLE_RATE as f64 / output_rate as f64;
+        if step <= 0.0 {
+            buffer.fill(0.0);
+            return;
+        }
+        if step == 1.0 && self.resampler.is_idle() {
+            self.mix(buffer);
+            return;
+        }
+        let mut resampler = std::mem::take(&mut self.resampler);
+        resampler.render(buffer, step, |frames| self.mix(frames));
+        self.resampler = resampler;
+    }
+
+    pub fn render_frames(&mut self, frame_count: usize) -> Vec<f32> {
+        let mut buffer = vec![0.0; frame_count * CHANNEL_COUNT];
+        self.render(&mut buffer);
+        buffer
+    }
+
+    // Sum the programs on both decks, each scaled by its deck's gain, and the players, each
+    // scaled and panned by its own gain and pan.
+    fn mix(&mut self, buffer: &mut [f32]) {
+        buffer.fill(0.0);
+        self.deck_buffer.resize(buffer.len(), 0.0);
+        for deck in [self.active_deck, self.previous_deck].into_iter().flatten() {
+            let Some(node) = self.programs.get_mut(&deck.program_no) else {
+                continue;
+            };
+            self.deck_buffer.fill(0.0);
+            node.fill_buffer(&mut self.deck_buffer);
+            buffer
+                .iter_mut()
+                .zip(self.deck_buffer.iter())
+                .for_each(|(sample, deck_sample)| *sample += deck_sample * deck.gain);
+        }
+        for player in self.players.values_mut() {
+            self.deck_buffer.fill(0.0);
+            player.node.fill_buffer(&mut self.deck_buffer);
+            let channel_gains = player.channel_gains();
+            for (frame, player_frame) in buffer
+                .chunks_mut(CHANNEL_COUNT)
+                .zip(self.deck_buffer.chunks(CHANNEL_COUNT))
+            {
+                for ((sample, player_sample), gain) in
+                    frame.iter_mut().zip(player_frame).zip(channel_gains)
+                {
+                    *sample += player_sample * gain;
+                }
+            }
+        }
+    }
+}
+
+// Advance a program that isn't playing yet by rendering and discarding the given length of audio.
+pub fn fast_forward(node: &mut GraphNode, seconds: f64) {
+    let mut frames_left = (seconds.max(0.0) * SAMPLE_RATE as f64) as usize;
+    let mut buffer = vec![0.0; FAST_FORWARD_CHUNK_FRAMES * CHANNEL_COUNT];
+    while frames_left > 0 {
+        let chunk_frames = frames_left.min(FAST_FORWARD_CHUNK_FRAMES);
+        let chunk = &mut buffer[..chunk_frames * CHANNEL_COUNT];
+        chunk.fill(0.0);
+        node.fill_buffer(chunk);
+        frames_left -= chunk_frames;
+    }
+}
+
+// Plays mixed audio back at a different rate than it was mixed at, filtering it with a windowed
+// sinc. When frames are consumed faster than they are played, such as on a 44.1 kHz device or when
+// speeding up, the cutoff is lowered to the output's Nyquist frequency so that frequencies the
+// output can't hold are removed rather than aliased.
+#[derive(Default)]
+struct Resampler {
+    // Mixed frames that the filter can still reach, interleaved
+    frames: Vec<f32>,
+    // Position of the next output frame, in frames from the start of the mixed frames
+    position: f64,
+    kernel: Option<SincKernel>,
+}
+
+impl Resampler {
+    fn is_idle(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    fn render(&mut self, output: &mut [f32], step: f64, mut mix: impl FnMut(&mut [f32])) {
+        let cutoff = (1.0 / step).min(1.0);
+        if self
+            .kernel
+            .as_ref()
+            .is_none_or(|kernel| kernel.cutoff != cutoff)
+        {
+            self.kernel = Some(SincKernel::new(cutoff));
+        }
+        let Some(kernel) = self.kernel.as_ref() else {
+            return;
+        };
+        if self.frames.is_empty() {
+            // The filter reaches back into silence before the first mixed frame
+            self.frames
+                .resize((SINC_HALF_TAPS - 1) * CHANNEL_COUNT, 0.0);
+            self.position = (SINC_HALF_TAPS - 1) as f64;
+        }
+        for frame in output.chunks_mut(CHANNEL_COUNT) {
+            let index = self.position as usize;
+            let phase = ((self.position - index as f64) * SINC_PHASES as f64) as usize;
+            while self.frames.len() < (index + SINC_HALF_TAPS + 1) * CHANNEL_COUNT {
+                let start = self.frames.len();
+                self.frames
+                    .resize(start + RESAMPLE_CHUNK_FRAMES * CHANNEL_COUNT, 0.0);
+                mix(&mut self.frames[start..]);
+            }
+            frame.fill(0.0);
+            let first_frame = index + 1 - SINC_HALF_TAPS;
+            for (tap, weight) in kernel.weights(phase).iter().enumerate() {
+                let source_frame = &self.frames[(first_frame + tap) * CHANNEL_COUNT..];
+                for (sample, source_sample) in frame.iter_mut().zip(source_frame) {
+                    *sample += source_sample * weight;
+                }
+            }
+            self.position += step;
+        }
+        let passed_frames = (self.position as usize + 1)
+            .saturating_sub(SINC_HALF_TAPS)
+            .min(self.frames.len() / CHANNEL_COUNT);
+        self.frames.drain(..passed_frames * CHANNEL_COUNT);
+        self.position -= passed_frames as f64;
+    }
+}
+
+// Blackman-windowed sinc filter weights for every phase between two frames, each phase normalized
+// so that a constant signal passes through unchanged
+struct SincKernel {
+    // Cutoff as a fraction of the mixer's Nyquist frequency
+    cutoff: f64,
+    weights: Vec<f32>,
+}
+
+impl SincKernel {
+    fn new(cutoff: f64) -> Self {
+        let tap_count = SINC_HALF_TAPS * 2;
+        let mut weights = Vec::with_capacity(SINC_PHASES * tap_count);
+        for phase in 0..SINC_PHASES {
+            let fraction = phase as f64 / SINC_PHASES as f64;
+            let phase_weights: Vec<f64> = (0..tap_count)
+                .map(|tap| {
+                    let distance = tap as f64 + 1.0 - SINC_HALF_TAPS as f64 - fraction;
+                    let x = distance / SINC_HALF_TAPS as f64;
+                    let window = 0.42
+                        + 0.5 * (std::f64::consts::PI * x).cos()
+                        + 0.08 * (2.0 * std::f64::consts::PI * x).cos();
+                    cutoff * sinc(cutoff * distance) * window
+                })
+                .collect();
+            let sum: f64 = phase_weights.iter().sum();
+            weights.extend(phase_weights.iter().map(|weight| (weight / sum) as f32));
+        }
+        Self { cutoff, weights }
+    }
+
+    fn weights(&self, phase: usize) -> &[f32] {
+        let tap_count = SINC_HALF_TAPS * 2;
+        &self.weights[phase * tap_count..(phase + 1) * tap_count]
+    }
+}
+
+// Exact at whole numbers, so that frames pass through untouched when no resampling is needed
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else if x.fract() == 0.0 {
+        0.0
+    } else {
+        (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stereo frames counting up from zero, with the right channel negated
+    fn counting_mix() -> impl FnMut(&mut [f32]) {
+        let mut next_frame = 0.0;
+        move |frames: &mut [f32]| {
+            for frame in frames.chunks_mut(CHANNEL_COUNT) {
+                frame[0] = next_frame;
+                frame[1] = -next_frame;
+                next_frame += 1.0;
+            }
+        }
+    }
+
+    #[test]
+    fn resampler_at_unit_step_passes_frames_through() {
+        let mut resampler = Resampler::default();
+        let mut mix = counting_mix();
+        let mut output = vec![0.0; 4 * CHANNEL_COUNT];
+        resampler.render(&mut output, 1.0, &mut mix);
+        assert_eq!(output, vec![0.0, -0.0, 1.0, -1.0, 2.0, -2.0, 3.0, -3.0]);
+        // Playback continues from where the previous buffer stopped
+        resampler.render(&mut output, 1.0, &mut mix);
+        assert_eq!(output[..2], [4.0, -4.0]);
+    }
+
+    // Left channel of a sine wave at the given frequency, in cycles per mixed frame
+    fn sine_mix(frequency: f64) -> impl FnMut(&mut [f32]) {
+        let mut next_frame = 0;
+        move |frames: &mut [f32]| {
+            for frame in frames.chunks_mut(CHANNEL_COUNT) {
+                let angle = 2.0 * std::f64::consts::PI * frequency * next_frame as f64;
+                frame[0] = angle.sin() as f32;
+                frame[1] = 0.0;
+                next_frame += 1;
+            }
+        }
+    }
+
+    fn left_channel(output: &[f32]) -> Vec<f32> {
+        output.iter().step_by(CHANNEL_COUNT).copied().collect()
+    }
+
+    #[test]
+    fn resampler_follows_a_slow_wave_between_frames() {
+        let frequency = 1.0 / 64.0;
+        let mut resampler = Resampler::default();
+        let mut mix = sine_mix(frequency);
+        let mut output = vec![0.0; 256 * CHANNEL_COUNT];
+        resampler.render(&mut output, 0.5, &mut mix);
+        // Skip the start, where the filter still reaches back into silence
+        for (index, sample) in left_channel(&output)
+            .iter()
+            .enumerate()
+            .skip(SINC_HALF_TAPS * 2)
+        {
+            let expected = (2.0 * std::f64::consts::PI * frequency * index as f64 * 0.5).sin();
+            assert!((*sample as f64 - expected).abs() < 0.01, "frame {}", index);
+        }
+        // Speeding up skips frames, continuing from where the previous buffer stopped
+        resampler.render(&mut output, 2.0, &mut mix);
+        for (index, sample) in left_channel(&output).iter().enumerate() {
+            let position = 128.0 + index as f64 * 2.0;
+            let expected = (2.0 * std::f64::consts::PI * frequency * position).sin();
+            assert!((*sample as f64 - expected).abs() < 0.01, "frame {}", index);
+        }
+    }
+
+    #[test]
+    fn resampler_filters_out_what_the_output_cannot_hold() {
+        // Above the output's Nyquist frequency when every other frame is skipped
+        let mut resampler = Resampler::default();
+        let mut mix = sine_mix(0.4);
+        let mut output = vec![0.0; 512 * CHANNEL_COUNT];
+        resampler.render(&mut output, 2.0, &mut mix);
+        let left = left_channel(&output);
+        let settled = &left[SINC_HALF_TAPS..];
+        let power =
+            settled.iter().map(|sample| sample * sample).sum::<f32>() / settled.len() as f32;
+        assert!(power.sqrt() < 0.01, "aliased level {}", power.sqrt());
+    }
+
+    #[test]
+    fn paused_mixer_renders_silence() {
+        let mut mixer = Mixer::new();
+        mixer.set_playback_rate(0.0);
+        let mut output = vec![1.0; 8 * CHANNEL_COUNT];
+        mixer.render(&mut output);
+        assert!(output.iter().all(|sample| *sample == 0.0));
+        assert!(mixer.resampler.is_idle());
+    }
+}
@@ -0,0 +1,65 @@
+use bevy_midi_graph::{RenderLength, render_graph_file_to_wav};
+use std::path::Path;
+
+const USAGE: &str =
+    "Usage: render_graph <graph path> <output.wav> [--seconds N] [--max-seconds N] [--assets DIR]
+
+Renders a graph file without opening an audio device. The graph path and the paths of its
+MIDI, SoundFont and WAV dependencies are resolved relative to the assets directory, which
+defaults to ./assets. Without --seconds, rendering stops a second after the longest MIDI track
+in the graph ends, up to --max-seconds (default 300).";
+
+const DEFAULT_MAX_SECONDS: f32 = 300.0;
+
+pub fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(message) = run(&args) {
+        eprintln!("{}\n\n{}", message, USAGE);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let mut positional = vec![];
+    let mut seconds = None;
+    let mut max_seconds = DEFAULT_MAX_SECONDS;
+    let mut asset_root = "assets".to_owned();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seconds" => seconds = Some(parse_value(arg, args.next())?),
+            "--max-seconds" => max_seconds = parse_value(arg, args.next())?,
+            "--assets" => {
+                asset_root = args
+                    .next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))?
+                    .to_owned()
+            }
+            _ => positional.push(arg.as_str()),
+        }
+    }
+    let [graph_path, output_path] = positional[..] else {
+        return Err("Expected a graph path and an output path".to_owned());
+    };
+    let length = match seconds {
+        Some(seconds) => RenderLength::Seconds(seconds),
+        None => RenderLength::UntilTrackEnds { max_seconds },
+    };
+    println!("Rendering {} to {}...", graph_path, output_path);
+    render_graph_file_to_wav(
+        Path::new(&asset_root),
+        graph_path,
+        length,
+        Path::new(output_path),
+    )
+    .map_err(|err| format!("Render failed: {:?}", err))?;
+    println!("Done");
+    Ok(())
+}
+
+fn parse_value<T: std::str::FromStr>(arg: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("Missing value for {}", arg))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", arg, value))
+}
@@ -0,0 +1,235 @@
+use crate::{
+    MidiGraphPlayer, MidiGraphPlayers, NodeRef,
+    message::{MidiGraphCommand, MidiGraphCommandFailed},
+};
+use bevy::{
+    prelude::*,
+    reflect::{ParsedPath, PartialReflect, ReflectPath},
+};
+use midi_graph::{Error, effect::ModulationProperty};
+
+type BindingSource = Box<dyn Fn(&World, Entity) -> Option<f32> + Send + Sync>;
+
+// Changes smaller than this are not sent, so that values holding steady cost nothing
+const CHANGE_THRESHOLD: f32 = 1.0e-4;
+
+/// Drives a parameter of a node from a value read out of the world each frame. The node is given
+/// by id or by its name in the graph.
+pub struct ParameterBinding {
+    pub node: NodeRef,
+    pub property: ModulationProperty,
+    source: BindingSource,
+    sent_value: Option<f32>,
+}
+
+impl ParameterBinding {
+    // Bind to a value computed from the world and the entity holding the binding. The source
+    // returns None when there is nothing to send this frame.
+    pub fn new(
+        node: impl Into<NodeRef>,
+        property: ModulationProperty,
+        source: impl Fn(&World, Entity) -> Option<f32> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            node: node.into(),
+            property,
+            source: Box::new(source),
+            sent_value: None,
+        }
+    }
+
+    // Bind to a component on the entity holding the binding
+    pub fn from_component<C: Component>(
+        node: impl Into<NodeRef>,
+        property: ModulationProperty,
+        value: impl Fn(&C) -> f32 + Send + Sync + 'static,
+    ) -> Self {
+        Self::new(node, property, move |world, entity| {
+            world.get::<C>(entity).map(&value)
+        })
+    }
+
+    // Bind to a resource
+    pub fn from_resource<R: Resource>(
+        node: impl Into<NodeRef>,
+        property: ModulationProperty,
+        value: impl Fn(&R) -> f32 + Send + Sync + 'static,
+    ) -> Self {
+        Self::new(node, property, move |world, _| {
+            world.get_resource::<R>().map(&value)
+        })
+    }
+
+    // Bind to a number in a reflected component on the entity holding the binding, given by a
+    // field path such as "speed.x". Fails if the path can't be parsed.
+    pub fn from_reflect_path<C: Component + Reflect>(
+        node: impl Into<NodeRef>,
+        property: ModulationProperty,
+        path: &str,
+    ) -> Result<Self, Error> {
+        let path = parse_reflect_path(path)?;
+        Ok(Self::new(node, property, move |world, entity| {
+            reflect_path_value(world.get::<C>(entity)?, &path)
+        }))
+    }
+
+    // Bind to a number in a reflected resource, given by a field path
+    pub fn from_resource_reflect_path<R: Resource + Reflect>(
+        node: impl Into<NodeRef>,
+        property: ModulationProperty,
+        path: &str,
+    ) -> Result<Self, Error> {
+        let path = parse_reflect_path(path)?;
+        Ok(Self::new(node, property, move |world, _| {
+            reflect_path_value(world.get_resource::<R>()?, &path)
+        }))
+    }
+}
+
+fn parse_reflect_path(path: &str) -> Result<ParsedPath, Error> {
+    ParsedPath::parse(path)
+        .map_err(|err| Error::User(format!("Invalid field path \"{}\": {}", path, err)))
+}
+
+// Read a number of any primitive type from a reflected value. A path that leads to something
+// else, or nowhere, such as into an enum variant that isn't the current one, gives nothing to
+// send.
+fn reflect_path_value(root: &dyn PartialReflect, path: &ParsedPath) -> Option<f32> {
+    let value = path.reflect_element(root).ok()?;
+    value
+        .try_downcast_ref::<f32>()
+        .copied()
+        .or_else(|| value.try_downcast_ref::<f64>().map(|value| *value as f32))
+        .or_else(|| value.try_downcast_ref::<i8>().map(|value| *value as f32))
+        .or_else(|| value.try_downcast_ref::<i16>().map(|value| *value as f32))
+        .or_else(|| value.try_downcast_ref::<i32>().map(|value| *value as f32))
+        .or_else(|| value.try_downcast_ref::<i64>().map(|value| *value as f32))
+        .or_else(|| value.try_downcast_ref::<u8>().map(|value| *value as f32))
+        .or_else(|| value.try_downcast_ref::<u16>().map(|value| *value as f32))
+        .or_else(|| value.try_downcast_ref::<u32>().map(|value| *value as f32))
+        .or_else(|| value.try_downcast_ref::<u64>().map(|value| *value as f32))
+        .or_else(|| value.try_downcast_ref::<usize>().map(|value| *value as f32))
+}
+
+/// Parameter bindings that are sent whenever their values change. When the entity has a
+/// [`MidiGraphPlayer`], they are sent to its graph; otherwise they are sent to the playing
+/// program as [`MidiGraphCommand`]s.
+#[derive(Component, Default)]
+pub struct MidiGraphBindings {
+    bindings: Vec<ParameterBinding>,
+}
+
+impl MidiGraphBindings {
+    pub fn new(bindings: Vec<ParameterBinding>) -> Self {
+        Self { bindings }
+    }
+
+    pub fn with(mut self, binding: ParameterBinding) -> Self {
+        self.bindings.push(binding);
+        self
+    }
+
+    pub fn push(&mut self, binding: ParameterBinding) {
+        self.bindings.push(binding);
+    }
+
+    // Sources can read anything in the world, so bindings are read in an exclusive system
+    pub fn apply_bindings(world: &mut World) {
+        let mut binding_query = world.query::<(Entity, &MidiGraphBindings, Has<MidiGraphPlayer>)>();
+        let mut changes = vec![];
+        for (entity, bindings, has_player) in binding_query.iter(world) {
+            for (index, binding) in bindings.bindings.iter().enumerate() {
+                let Some(value) = (binding.source)(world, entity) else {
+                    continue;
+                };
+                if binding
+                    .sent_value
+                    .is_some_and(|sent_value| (sent_value - value).abs() < CHANGE_THRESHOLD)
+                {
+                    continue;
+                }
+                changes.push((entity, has_player, index, value));
+            }
+        }
+
+        for (entity, has_player, index, value) in changes {
+            let Some(bindings) = world.get::<MidiGraphBindings>(entity) else {
+                continue;
+            };
+            let binding = &bindings.bindings[index];
+            let command =
+                MidiGraphCommand::modulate(binding.node.clone(), binding.property.clone(), value);
+            if has_player {
+                // Players that haven't started yet are sent their values once they have
+                let players = world.resource::<MidiGraphPlayers>();
+                if !players.is_playing(entity) {
+                    continue;
+                }
+                if let Err(error) = players.send(entity, command) {
+                    world.write_message(MidiGraphCommandFailed { error });
+                }
+            } else {
+                world.write_message(command);
+            }
+            if let Some(mut bindings) = world.get_mut::<MidiGraphBindings>(entity) {
+                bindings.bindings[index].sent_value = Some(value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Reflect)]
+    struct Velocity {
+        x: f32,
+        y: f64,
+    }
+
+    #[derive(Reflect)]
+    enum Engine {
+        Running { rpm: u32 },
+        Stalled,
+    }
+
+    #[derive(Reflect)]
+    struct Vehicle {
+        speed: Velocity,
+        gear: u8,
+        health: i32,
+        engine: Engine,
+        name: String,
+    }
+
+    #[test]
+    fn reflect_paths_read_numbers_of_any_type() {
+        let mut vehicle = Vehicle {
+            speed: Velocity { x: 2.5, y: -1.0 },
+            gear: 3,
+            health: -20,
+            engine: Engine::Running { rpm: 3000 },
+            name: "kart".to_owned(),
+        };
+        let value = |vehicle: &Vehicle, path: &str| {
+            reflect_path_value(vehicle, &parse_reflect_path(path).unwrap())
+        };
+        assert_eq!(value(&vehicle, "speed.x"), Some(2.5));
+        assert_eq!(value(&vehicle, "speed.y"), Some(-1.0));
+        assert_eq!(value(&vehicle, "gear"), Some(3.0));
+        assert_eq!(value(&vehicle, "health"), Some(-20.0));
+        assert_eq!(value(&vehicle, "engine.rpm"), Some(3000.0));
+        // Nothing is sent for values that aren't numbers, or aren't there
+        assert_eq!(value(&vehicle, "name"), None);
+        assert_eq!(value(&vehicle, "speed.z"), None);
+        vehicle.engine = Engine::Stalled;
+        assert_eq!(value(&vehicle, "engine.rpm"), None);
+    }
+
+    #[test]
+    fn malformed_reflect_paths_are_rejected() {
+        assert!(parse_reflect_path("speed.").is_err());
+        assert!(parse_reflect_path("[x]").is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use crate::{
+    MidiFileSource, MidiGraph, MidiGraphAudioContext, playback::PlaybackTracker,
+    timeline::MidiTimeline,
+};
+use bevy::prelude::*;
+use std::time::Duration;
+
+/// Musical time of the playing program, updated every frame from the lowest-numbered `Midi`
+/// node in its graph.
+///
+/// Positions are compensated for [`MusicClock::output_latency`], so they describe what is
+/// currently being heard rather than what the mixer has just rendered.
+#[derive(Resource, Clone, Debug)]
+pub struct MusicClock {
+    /// How far audio output trails the mixer. With the device backend, this is measured from the
+    /// output stream and updated every frame. With the other backends, set it to line visuals up
+    /// with however the rendered audio is played.
+    pub output_latency: Duration,
+    program_no: Option<usize>,
+    node_id: Option<u64>,
+    is_running: bool,
+    tick: u64,
+    mixer_tick: u64,
+    mixer_seconds: f64,
+    seconds: f64,
+    beats: f64,
+    tempo_bpm: f64,
+    time_signature: (u8, u8),
+    bar: u64,
+    beat: u32,
+    beat_fraction: f32,
+}
+
+impl Default for MusicClock {
+    fn default() -> Self {
+        Self {
+            output_latency: Duration::ZERO,
+            program_no: None,
+            node_id: None,
+            is_running: false,
+            tick: 0,
+            mixer_tick: 0,
+            mixer_seconds: 0.0,
+            seconds: 0.0,
+            beats: 0.0,
+            tempo_bpm: 120.0,
+            time_signature: (4, 4),
+            bar: 0,
+            beat: 0,
+            beat_fraction: 0.0,
+        }
+    }
+}
+
+impl MusicClock {
+    pub fn update(
+        mut clock: ResMut<MusicClock>,
+        mut tracker: ResMut<PlaybackTracker>,
+        audio_context: Res<MidiGraphAudioContext>,
+        asset_server: Res<AssetServer>,
+        graphs: Res<Assets<MidiGraph>>,
+        midi_assets: Res<Assets<MidiFileSource>>,
+    ) {
+        if let Some(output_latency) = audio_context.output_latency() {
+            clock.output_latency = output_latency;
+        }
+        let program_no = audio_context.playing_program();
+        let source = program_no
+            .and_then(|program_no| audio_context.program_asset(program_no))
+            .and_then(|asset_handle| graphs.get(asset_handle))
+            .and_then(|graph| {
+                graph
+                    .midi_sources
+                    .iter()
+                    .min_by_key(|(node_id, _)| **node_id)
+            });
+        let Some((node_id, source)) = source else {
+            clock.stop(program_no, None);
+            return;
+        };
+        let node_id = *node_id;
+        let Some(position) = audio_context.capture_midi_position(node_id) else {
+            clock.stop(program_no, Some(node_id));
+            return;
+        };
+        let timeline = tracker.timeline(&asset_server, &midi_assets, source);
+        clock.program_no = program_no;
+        clock.node_id = Some(node_id);
+        clock.is_running = true;
+        clock.mixer_tick = position.tick;
+        match timeline {
+            Some(timeline) => clock.follow_timeline(&timeline, position.tick),
+            None => clock.follow_position(
+                position.tick,
+                position.ticks_per_beat,
+                position.microseconds_per_beat,
+                position.beats_per_bar,
+            ),
+        }
+    }
+
+    fn stop(&mut self, program_no: Option<usize>, node_id: Option<u64>) {
+        self.program_no = program_no;
+        self.node_id = node_id;
+        self.is_running = false;
+    }
+
+    fn follow_timeline(&mut self, timeline: &MidiTimeline, mixer_tick: u64) {
+        self.mixer_seconds = timeline.seconds_at(mixer_tick);
+        self.seconds = (self.mixer_seconds - self.output_latency.as_secs_f64()).max(0.0);
+        self.tick = timeline.tick_at_seconds(self.seconds).min(mixer_tick);
+        self.beats = self.tick as f64 / timeline.ticks_per_beat.max(1) as f64;
+        self.tempo_bpm = 60.0e6 / timeline.microseconds_per_beat_at(self.tick) as f64;
+        let bar_beat = timeline.bar_beat_at(self.tick);
+        self.time_signature = bar_beat.time_signature;
+        self.bar = bar_beat.bar;
+        self.beat = bar_beat.beat;
+        self.beat_fraction = bar_beat.beat_fraction;
+    }
+
+    // Without the track's timeline, the tempo reported by the node is assumed to have been
+    // constant since the start of the track.
+    fn follow_position(
+        &mut self,
+        mixer_tick: u64,
+        ticks_per_beat: u32,
+        microseconds_per_beat: u32,
+        beats_per_bar: u32,
+    ) {
+        let microseconds_per_beat = match microseconds_per_beat {
+            0 => 500000,
+            microseconds_per_beat => microseconds_per_beat,
+        };
+        let ticks_per_second = ticks_per_beat as f64 * 1.0e6 / microseconds_per_beat as f64;
+        let latency_ticks = (self.output_latency.as_secs_f64() * ticks_per_second) as u64;
+        let beats_per_bar = beats_per_bar.max(1);
+        self.tick = mixer_tick.saturating_sub(latency_ticks);
+        self.mixer_seconds = mixer_tick as f64 / ticks_per_second;
+        self.seconds = self.tick as f64 / ticks_per_second;
+        self.beats = self.tick as f64 / ticks_per_beat as f64;
+        self.tempo_bpm = 60.0e6 / microseconds_per_beat as f64;
+        self.time_signature = (beats_per_bar.min(u8::MAX as u32) as u8, 4);
+        let beat_index = self.tick / ticks_per_beat as u64;
+        self.bar = beat_index / beats_per_bar as u64;
+        self.beat = (beat_index % beats_per_bar as u64) as u32;
+        self.beat_fraction = (self.tick % ticks_per_beat as u64) as f32 / ticks_per_beat as f32;
+    }
+
+    // The program whose position is being followed
+    pub fn program_no(&self) -> Option<usize> {
+        self.program_no
+    }
+
+    // The Midi node whose position is being followed
+    pub fn node_id(&self) -> Option<u64> {
+        self.node_id
+    }
+
+    // Whether the position was read this frame. When false, the other values are those of the
+    // last frame the position could be read.
+    pub fn is_running(&self) -> bool {
+        self.is_running
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    // Tick most recently reached by the mixer, ahead of what is heard by the output latency
+    pub fn mixer_tick(&self) -> u64 {
+        self.mixer_tick
+    }
+
+    // Time from the start of the track to the mixer tick
+    pub fn mixer_seconds(&self) -> f64 {
+        self.mixer_seconds
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.seconds
+    }
+
+    // Quarter-note beats from the start of the track to the tick, including the fraction of the
+    // current one
+    pub fn beats(&self) -> f64 {
+        self.beats
+    }
+
+    pub fn tempo_bpm(&self) -> f64 {
+        self.tempo_bpm
+    }
+
+    // Numerator and denominator of the time signature in effect
+    pub fn time_signature(&self) -> (u8, u8) {
+        self.time_signature
+    }
+
+    // Bar since the start of the track, counted from zero
+    pub fn bar(&self) -> u64 {
+        self.bar
+    }
+
+    // Beat within the current bar, counted from zero
+    pub fn beat(&self) -> u32 {
+        self.beat
+    }
+
+    // How far playback is through the current beat, from 0 to 1
+    pub fn beat_fraction(&self) -> f32 {
+        self.beat_fraction
+    }
+
+    // Length of one quarter note at the current tempo
+    pub fn beat_duration(&self) -> Duration {
+        Duration::from_secs_f64(60.0 / self.tempo_bpm)
+    }
+}
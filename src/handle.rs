@@ -0,0 +1,138 @@
+use crate::{MidiGraph, MidiGraphAudioContext, MidiPosition, message::MidiGraphCommand};
+use bevy::asset::AssetId;
+use midi_graph::{Balance, Event, effect::ModulationProperty, midi::CueData};
+
+/// A node in a graph, given by its node id or its name.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NodeRef {
+    Id(u64),
+    Name(String),
+}
+
+impl From<u64> for NodeRef {
+    fn from(node_id: u64) -> Self {
+        NodeRef::Id(node_id)
+    }
+}
+
+impl From<&str> for NodeRef {
+    fn from(name: &str) -> Self {
+        NodeRef::Name(name.to_owned())
+    }
+}
+
+impl From<String> for NodeRef {
+    fn from(name: String) -> Self {
+        NodeRef::Name(name)
+    }
+}
+
+/// A `Midi` node in a loaded graph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MidiNodeHandle {
+    pub graph: AssetId<MidiGraph>,
+    pub node_id: u64,
+}
+
+impl MidiNodeHandle {
+    // Jump to a cue anchor once the current section allows it
+    pub fn seek_cue(&self, anchor: u32) -> MidiGraphCommand {
+        MidiGraphCommand::to_node(self.node_id, Event::CueData(CueData::SeekWhenIdeal(anchor)))
+            .for_graph(self.graph)
+    }
+
+    pub fn set_volume(&self, volume: f32) -> MidiGraphCommand {
+        MidiGraphCommand::to_node(self.node_id, Event::Volume(volume)).for_graph(self.graph)
+    }
+
+    // Position of this node, if its graph is the playing program
+    pub fn position(&self, audio_context: &MidiGraphAudioContext) -> Option<MidiPosition> {
+        if audio_context.playing_graph() != Some(self.graph) {
+            return None;
+        }
+        audio_context.capture_midi_position(self.node_id)
+    }
+}
+
+/// An `AdsrEnvelope` node in a loaded graph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EnvelopeHandle {
+    pub graph: AssetId<MidiGraph>,
+    pub node_id: u64,
+}
+
+impl EnvelopeHandle {
+    pub fn set_volume(&self, volume: f32) -> MidiGraphCommand {
+        MidiGraphCommand::to_node(self.node_id, Event::Volume(volume)).for_graph(self.graph)
+    }
+
+    pub fn modulate(&self, property: ModulationProperty, value: f32) -> MidiGraphCommand {
+        MidiGraphCommand::modulate(self.node_id, property, value).for_graph(self.graph)
+    }
+}
+
+/// A `SampleLoop` node in a loaded graph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SampleLoopHandle {
+    pub graph: AssetId<MidiGraph>,
+    pub node_id: u64,
+}
+
+impl SampleLoopHandle {
+    pub fn set_volume(&self, volume: f32) -> MidiGraphCommand {
+        MidiGraphCommand::to_node(self.node_id, Event::Volume(volume)).for_graph(self.graph)
+    }
+
+    // Pan from -1 (left) to 1 (right)
+    pub fn set_pan(&self, pan: f32) -> MidiGraphCommand {
+        MidiGraphCommand::to_node(self.node_id, Event::SourceBalance(Balance::Pan(pan)))
+            .for_graph(self.graph)
+    }
+}
+
+// Handles are only given out for nodes of the matching type, so events meant for one kind of
+// node can't be sent to another by mistake. They keep the id of the graph asset they were given
+// out for, which the graph can't know itself, and their commands fail if that graph isn't the
+// one they are sent to.
+impl MidiGraph {
+    pub fn midi_node(
+        &self,
+        graph: impl Into<AssetId<MidiGraph>>,
+        node: impl Into<NodeRef>,
+    ) -> Option<MidiNodeHandle> {
+        let node_id = self.typed_node_id(node.into(), "Midi")?;
+        Some(MidiNodeHandle {
+            graph: graph.into(),
+            node_id,
+        })
+    }
+
+    pub fn envelope(
+        &self,
+        graph: impl Into<AssetId<MidiGraph>>,
+        node: impl Into<NodeRef>,
+    ) -> Option<EnvelopeHandle> {
+        let node_id = self.typed_node_id(node.into(), "AdsrEnvelope")?;
+        Some(EnvelopeHandle {
+            graph: graph.into(),
+            node_id,
+        })
+    }
+
+    pub fn sample_loop(
+        &self,
+        graph: impl Into<AssetId<MidiGraph>>,
+        node: impl Into<NodeRef>,
+    ) -> Option<SampleLoopHandle> {
+        let node_id = self.typed_node_id(node.into(), "SampleLoop")?;
+        Some(SampleLoopHandle {
+            graph: graph.into(),
+            node_id,
+        })
+    }
+
+    fn typed_node_id(&self, node: NodeRef, node_type: &str) -> Option<u64> {
+        let node_id = self.resolve_node(&node)?;
+        (self.node_types.get(&node_id)? == node_type).then_some(node_id)
+    }
+}
@@ -1,18 +1,61 @@
 mod asset;
+mod backend;
+mod binding;
+mod clock;
+mod handle;
+mod message;
+mod mixer_thread;
+mod playback;
+mod player;
+mod progress;
+mod render;
 mod resource;
+mod schedule;
+mod spatial;
 mod state;
+mod state_music;
+mod timeline;
+mod transition;
+mod tween;
 
 use bevy::prelude::*;
 
+#[cfg(feature = "ron")]
+pub use asset::graph_formats::MidiGraphRonLoader;
+#[cfg(feature = "yaml")]
+pub use asset::graph_formats::MidiGraphYamlLoader;
 pub use asset::{
     AssetError,
-    graph::{MidiGraph, MidiGraphLoader},
+    graph::{MidiGraph, MidiGraphLoader, MidiGraphLoaderSettings, MidiNodeSource},
     loader::{AssetType, GraphAssetLoader},
     midi::{MidiFileSource, MidiFileSourceLoader},
     sf2::{Sf2FileSource, Sf2FileSourceLoader},
+    validate::{GraphLoadError, GraphValidationError},
     wave::{WaveFileSource, WaveFileSourceLoader},
 };
+pub use backend::{AudioBackend, OfflineAudioOutput, OfflineConfig, VirtualTimeSync};
+pub use binding::{MidiGraphBindings, ParameterBinding};
+pub use clock::MusicClock;
+pub use handle::{EnvelopeHandle, MidiNodeHandle, NodeRef, SampleLoopHandle};
+pub use message::{
+    MidiCueReached, MidiGraphCommand, MidiGraphCommandFailed, MidiGraphPlayerFailed, MidiLooped,
+    MidiNoteFired, MidiTrackEnded, ProgramLoadFailed, ProgramReady, ScheduledMidiGraphCommand,
+    TweenParameter,
+};
+pub use playback::MidiPosition;
+pub use player::{MidiGraphPlayer, MidiGraphPlayers};
+pub use progress::{LoadProgress, ProgramLoadProgress};
+pub use render::{
+    FileAssetLoader, RenderLength, render_config, render_config_to_wav, render_graph_file_to_wav,
+    render_graph_to_wav, write_wav,
+};
 pub use resource::MidiGraphAudioContext;
+pub use schedule::MusicalTime;
+pub use spatial::{MidiGraphEmitter, MidiGraphListener};
+pub use state::AudioContextState;
+pub use state_music::MidiGraphAppExt;
+pub use transition::ProgramTransition;
+pub use tween::{TweenLength, TweenedParameter};
 
 pub mod midi {
     pub mod event {
@@ -33,10 +76,40 @@ pub mod midi {
     }
 }
 
-pub struct MidiGraphPlugin;
+/// System sets run by the plugin in `PostUpdate`.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MidiGraphSystems {
+    /// Delivers [`MidiGraphCommand`] messages to the mixer, along with any
+    /// [`ScheduledMidiGraphCommand`] whose moment has come.
+    SendCommands,
+    /// Pulls audio from the offline backends.
+    Render,
+}
+
+#[derive(Default)]
+pub struct MidiGraphPlugin {
+    pub backend: AudioBackend,
+    pub virtual_time_sync: VirtualTimeSync,
+}
 
 impl Plugin for MidiGraphPlugin {
     fn build(&self, app: &mut App) {
+        // Machines without a sound device still run the app, just without hearing it
+        let (backend, audio_context) = match MidiGraphAudioContext::new(&self.backend) {
+            Ok(audio_context) => (self.backend.clone(), audio_context),
+            Err(err) => {
+                warn!(
+                    "Could not start {:?} audio backend, falling back to Null: {:?}",
+                    self.backend, err
+                );
+                let audio_context = match MidiGraphAudioContext::new(&AudioBackend::Null) {
+                    Ok(audio_context) => audio_context,
+                    Err(err) => panic!("Could not start Null audio backend: {:?}", err),
+                };
+                (AudioBackend::Null, audio_context)
+            }
+        };
+        let players = MidiGraphPlayers::new(audio_context.mixer().clone());
         app.init_asset::<MidiGraph>()
             .init_asset_loader::<MidiGraphLoader>()
             .init_asset::<MidiFileSource>()
@@ -45,12 +118,98 @@ impl Plugin for MidiGraphPlugin {
             .init_asset_loader::<Sf2FileSourceLoader>()
             .init_asset::<WaveFileSource>()
             .init_asset_loader::<WaveFileSourceLoader>()
-            .init_resource::<resource::MidiGraphAudioContext>()
-            .insert_state(state::AudioContextState::None)
+            .insert_resource(audio_context)
+            .insert_resource(players)
+            .insert_resource(self.virtual_time_sync)
+            .add_message::<ProgramLoadFailed>()
+            .add_message::<ProgramReady>()
+            .add_message::<MidiGraphCommand>()
+            .add_message::<ScheduledMidiGraphCommand>()
+            .add_message::<MidiGraphCommandFailed>()
+            .add_message::<MidiGraphPlayerFailed>()
+            .add_message::<TweenParameter>()
+            .add_message::<MidiNoteFired>()
+            .add_message::<MidiCueReached>()
+            .add_message::<MidiLooped>()
+            .add_message::<MidiTrackEnded>()
+            .init_resource::<playback::PlaybackTracker>()
+            .init_resource::<MusicClock>()
+            .init_resource::<ProgramLoadProgress>()
+            .init_resource::<schedule::CommandSchedule>()
+            .init_resource::<tween::ParameterTweens>()
+            .insert_state(AudioContextState::None)
             .add_systems(
                 Update,
-                MidiGraphAudioContext::check_loading_asset
-                    .run_if(in_state(state::AudioContextState::Loading)),
+                (
+                    MidiGraphAudioContext::reload_modified_programs,
+                    MidiGraphAudioContext::finish_rebuilt_programs
+                        .run_if(MidiGraphAudioContext::has_rebuilding_programs),
+                    MidiGraphAudioContext::check_loading_asset
+                        .run_if(MidiGraphAudioContext::has_loading_programs),
+                    ProgramLoadProgress::update_progress,
+                )
+                    .chain(),
+            )
+            .add_systems(
+                Update,
+                (
+                    MidiGraphPlayers::stop_removed_players,
+                    MidiGraphPlayers::start_players,
+                    MidiGraphEmitter::update_emitters,
+                    MidiGraphBindings::apply_bindings,
+                    tween::ParameterTweens::advance_tweens,
+                )
+                    .chain(),
+            )
+            .add_systems(
+                Update,
+                MidiGraphAudioContext::advance_transition
+                    .run_if(MidiGraphAudioContext::has_transition),
+            )
+            .add_systems(
+                PreUpdate,
+                (
+                    playback::PlaybackTracker::emit_playback_notifications,
+                    MusicClock::update,
+                ),
+            )
+            .add_systems(
+                PostUpdate,
+                (
+                    schedule::CommandSchedule::deliver_scheduled_commands,
+                    MidiGraphAudioContext::send_commands,
+                )
+                    .chain()
+                    .in_set(MidiGraphSystems::SendCommands),
+            )
+            .add_systems(
+                PostUpdate,
+                MidiGraphAudioContext::sync_virtual_time.before(MidiGraphSystems::Render),
+            )
+            .configure_sets(
+                PostUpdate,
+                MidiGraphSystems::SendCommands.before(MidiGraphSystems::Render),
             );
+        #[cfg(feature = "ron")]
+        app.init_asset_loader::<MidiGraphRonLoader>();
+        #[cfg(feature = "yaml")]
+        app.init_asset_loader::<MidiGraphYamlLoader>();
+        match &backend {
+            AudioBackend::Device => {}
+            AudioBackend::Null => {
+                app.add_systems(
+                    PostUpdate,
+                    MidiGraphAudioContext::render_null_audio.in_set(MidiGraphSystems::Render),
+                );
+            }
+            AudioBackend::Offline(config) => {
+                app.insert_resource(OfflineAudioOutput::new(config))
+                    .add_systems(
+                        PostUpdate,
+                        MidiGraphAudioContext::render_offline_audio
+                            .in_set(MidiGraphSystems::Render),
+                    );
+            }
+        }
     }
 }
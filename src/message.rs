@@ -0,0 +1,230 @@
+use crate::{
+    MidiGraph, NodeRef,
+    playback::MidiPosition,
+    schedule::MusicalTime,
+    tween::{TweenLength, TweenedParameter},
+};
+use bevy::{asset::AssetPath, math::curve::EaseFunction, prelude::*};
+use midi_graph::{
+    Event, EventTarget, EventTiming, Message as GraphMessage, effect::ModulationProperty,
+};
+
+/// Written when a program being loaded could not be loaded or built.
+#[derive(Message, Debug)]
+pub struct ProgramLoadFailed {
+    pub program_no: usize,
+    // The graph or sub-asset that failed, if it could be identified
+    pub asset_path: Option<AssetPath<'static>>,
+    pub error: midi_graph::Error,
+}
+
+/// Written when a program has finished loading and is stored, ready to be switched to.
+#[derive(Message, Debug)]
+pub struct ProgramReady {
+    pub program_no: usize,
+}
+
+/// Written when the graph of a [`MidiGraphPlayer`](crate::MidiGraphPlayer) could not be loaded
+/// or built. The player is not retried until its graph handle changes.
+#[derive(Message, Debug)]
+pub struct MidiGraphPlayerFailed {
+    pub entity: Entity,
+    pub asset_path: Option<AssetPath<'static>>,
+    pub error: midi_graph::Error,
+}
+
+/// An event for the playing program, written by game systems and delivered to the mixer by the
+/// plugin at the end of each frame. Commands can also be sent to the graph of a
+/// [`MidiGraphPlayer`](crate::MidiGraphPlayer) with [`MidiGraphPlayers::send`](crate::MidiGraphPlayers::send).
+#[derive(Message)]
+pub struct MidiGraphCommand {
+    pub target: EventTarget,
+    pub event: Event,
+    pub timing: EventTiming,
+    /// When set, the command is sent to the node given this name in the graph it is sent to, in
+    /// place of the target.
+    pub node_name: Option<String>,
+    /// When set, the command fails unless the graph it is sent to was loaded from this asset.
+    /// Commands built from node handles are tied to the graph the handle was given out for.
+    pub graph: Option<AssetId<MidiGraph>>,
+}
+
+impl MidiGraphCommand {
+    pub fn new(target: EventTarget, event: Event, timing: EventTiming) -> Self {
+        Self {
+            target,
+            event,
+            timing,
+            node_name: None,
+            graph: None,
+        }
+    }
+
+    // Send to a node by its id or its name in the graph. A command for a name fails if the graph
+    // it is sent to has no node with that name.
+    pub fn to_node(node: impl Into<NodeRef>, event: Event) -> Self {
+        match node.into() {
+            NodeRef::Id(node_id) => Self::new(
+                EventTarget::SpecificNode(node_id),
+                event,
+                EventTiming::Imprecise,
+            ),
+            NodeRef::Name(name) => Self::to_named_node(name, event),
+        }
+    }
+
+    // Send to a node by its name in the graph. The command fails if the graph it is sent to has
+    // no node with this name.
+    pub fn to_named_node(name: impl Into<String>, event: Event) -> Self {
+        Self {
+            node_name: Some(name.into()),
+            ..Self::new(EventTarget::Broadcast, event, EventTiming::Imprecise)
+        }
+    }
+
+    pub fn broadcast(event: Event) -> Self {
+        Self::new(EventTarget::Broadcast, event, EventTiming::Imprecise)
+    }
+
+    // Set a modulated parameter, such as a filter cutoff or volume, on a node
+    pub fn modulate(node: impl Into<NodeRef>, property: ModulationProperty, value: f32) -> Self {
+        Self::to_node(node, Event::Modulate(property, value))
+    }
+
+    pub fn for_graph(mut self, graph: impl Into<AssetId<MidiGraph>>) -> Self {
+        self.graph = Some(graph.into());
+        self
+    }
+
+    pub(crate) fn into_message(self) -> GraphMessage {
+        GraphMessage {
+            target: self.target,
+            event: self.event,
+            timing: self.timing,
+        }
+    }
+}
+
+/// A [`MidiGraphCommand`] held back until a musical moment in the playing program, so that
+/// changes such as stingers land in time with the music.
+#[derive(Message)]
+pub struct ScheduledMidiGraphCommand {
+    pub command: MidiGraphCommand,
+    pub at: MusicalTime,
+}
+
+impl ScheduledMidiGraphCommand {
+    pub fn next_beat(command: MidiGraphCommand) -> Self {
+        Self {
+            command,
+            at: MusicalTime::NextBeat,
+        }
+    }
+
+    pub fn next_bar(command: MidiGraphCommand) -> Self {
+        Self {
+            command,
+            at: MusicalTime::NextBar,
+        }
+    }
+
+    pub fn at_bar_beat(bar: u64, beat: u32, command: MidiGraphCommand) -> Self {
+        Self {
+            command,
+            at: MusicalTime::At { bar, beat },
+        }
+    }
+}
+
+/// Ramps a node parameter to a target value, sending a new value every frame until it gets
+/// there. Starting another tween of the same parameter takes over from this one.
+#[derive(Message, Clone, Debug)]
+pub struct TweenParameter {
+    /// The [`MidiGraphPlayer`](crate::MidiGraphPlayer) entity whose graph holds the node, or
+    /// `None` for the playing program.
+    pub player: Option<Entity>,
+    /// The node, by id or by its name in the graph.
+    pub node: NodeRef,
+    pub parameter: TweenedParameter,
+    /// The value to start from. If a tween of this parameter has already sent a value, the new
+    /// tween carries on from that value instead, so that nothing jumps.
+    pub from: f32,
+    pub to: f32,
+    pub length: TweenLength,
+    pub easing: EaseFunction,
+}
+
+impl TweenParameter {
+    pub fn new(
+        node: impl Into<NodeRef>,
+        parameter: TweenedParameter,
+        from: f32,
+        to: f32,
+        length: TweenLength,
+    ) -> Self {
+        Self {
+            player: None,
+            node: node.into(),
+            parameter,
+            from,
+            to,
+            length,
+            easing: EaseFunction::Linear,
+        }
+    }
+
+    pub fn for_player(mut self, entity: Entity) -> Self {
+        self.player = Some(entity);
+        self
+    }
+
+    pub fn with_easing(mut self, easing: EaseFunction) -> Self {
+        self.easing = easing;
+        self
+    }
+}
+
+/// Written when a [`MidiGraphCommand`] could not be delivered to the mixer, or the graph it was
+/// sent to has no node with its node name.
+#[derive(Message, Debug)]
+pub struct MidiGraphCommandFailed {
+    pub error: midi_graph::Error,
+}
+
+// Playback notifications are derived by comparing the playing program's MIDI positions from frame
+// to frame, so they arrive up to a frame after the moment they describe.
+
+/// Written when a note in the playing program's MIDI track has started.
+#[derive(Message, Debug, Clone)]
+pub struct MidiNoteFired {
+    pub node_id: u64,
+    pub position: MidiPosition,
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: u8,
+}
+
+/// Written when playback passes a marker or cue point in the MIDI track.
+#[derive(Message, Debug, Clone)]
+pub struct MidiCueReached {
+    pub node_id: u64,
+    pub position: MidiPosition,
+    pub label: String,
+}
+
+/// Written when playback jumps, such as when a loop wraps or a cue is seeked to. Playback that
+/// moves forward further than the frame's time allows counts as a jump, so a seek to a later cue
+/// doesn't report every note it skips.
+#[derive(Message, Debug, Clone)]
+pub struct MidiLooped {
+    pub node_id: u64,
+    pub position: MidiPosition,
+    pub from_tick: u64,
+}
+
+/// Written when playback reaches the end of the MIDI track.
+#[derive(Message, Debug, Clone)]
+pub struct MidiTrackEnded {
+    pub node_id: u64,
+    pub position: MidiPosition,
+}
@@ -0,0 +1,373 @@
+use crate::backend::{AudioBackend, CHANNEL_COUNT, Mixer};
+use cpal::{
+    FromSample, I24, Sample, SampleFormat, SizedSample, Stream, StreamConfig,
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+};
+use crossbeam_channel::{Receiver, Sender};
+use midi_graph::Error;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+type MixerCommand = Box<dyn FnOnce(&mut Mixer) + Send>;
+
+// Frames in each block of audio passed from the mixer thread to the output stream
+const OUTPUT_BLOCK_FRAMES: usize = 256;
+// Blocks rendered ahead of the output stream, which the mixer thread can spend running commands
+// without the stream running dry
+const OUTPUT_BLOCK_COUNT: usize = 4;
+// Stored as the output latency until the output stream has measured it
+const LATENCY_NOT_MEASURED: u64 = u64::MAX;
+
+// Handle to a thread that owns the mixer. Everything else talks to the mixer by sending commands
+// to that thread. With the device backend, the thread also opens the output stream, which may
+// hold thread-affine handles, and keeps it fed with blocks of audio rendered ahead of time. The
+// stream's callback only takes blocks from a queue and hands them back to be refilled, so it never
+// waits on the mixer or on the game. Clones share the thread, which stops once every handle is
+// dropped.
+#[derive(Clone)]
+pub struct MixerThread {
+    commands: Sender<MixerCommand>,
+    is_offline: bool,
+    // Nanoseconds from the mixer rendering a frame to the device playing it, as last measured by
+    // the output stream
+    output_latency: Arc<AtomicU64>,
+}
+
+impl MixerThread {
+    pub fn start(backend: &AudioBackend) -> Result<Self, Error> {
+        let (command_sender, command_receiver) = crossbeam_channel::unbounded::<MixerCommand>();
+        let (started_sender, started_receiver) = crossbeam_channel::bounded(1);
+        let is_offline = !matches!(backend, AudioBackend::Device);
+        let output_latency = Arc::new(AtomicU64::new(LATENCY_NOT_MEASURED));
+        let stream_latency = output_latency.clone();
+        std::thread::Builder::new()
+            .name("midi-graph-mixer".to_owned())
+            .spawn(move || {
+                let mut mixer = Mixer::new();
+                if is_offline {
+                    let _ = started_sender.send(Ok(()));
+                    for command in command_receiver.iter() {
+                        command(&mut mixer);
+                    }
+                    return;
+                }
+                let output = match DeviceOutput::open(stream_latency) {
+                    Ok(output) => {
+                        let _ = started_sender.send(Ok(()));
+                        output
+                    }
+                    Err(err) => {
+                        let _ = started_sender.send(Err(err));
+                        return;
+                    }
+                };
+                output.run(&mut mixer, &command_receiver);
+            })?;
+        started_receiver
+            .recv()
+            .map_err(|_| Error::Internal("Mixer thread stopped while starting".to_owned()))??;
+        Ok(Self {
+            commands: command_sender,
+            is_offline,
+            output_latency,
+        })
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.is_offline
+    }
+
+    // How far the device's output trails the mixer, once the output stream has measured it
+    pub fn output_latency(&self) -> Option<Duration> {
+        match self.output_latency.load(Ordering::Relaxed) {
+            LATENCY_NOT_MEASURED => None,
+            nanoseconds => Some(Duration::from_nanos(nanoseconds)),
+        }
+    }
+
+    // Run a command on the mixer thread and wait for its result.
+    pub fn run<T: Send + 'static>(
+        &self,
+        command: impl FnOnce(&mut Mixer) -> T + Send + 'static,
+    ) -> Result<T, Error> {
+        let (reply_sender, reply_receiver) = crossbeam_channel::bounded(1);
+        self.post(move |mixer| {
+            let _ = reply_sender.send(command(mixer));
+        })?;
+        reply_receiver
+            .recv()
+            .map_err(|_| Error::Internal("Mixer thread stopped before replying".to_owned()))
+    }
+
+    // Queue a command on the mixer thread without waiting for it to run. Commands run in the
+    // order they are sent, whether posted or run.
+    pub fn post(&self, command: impl FnOnce(&mut Mixer) + Send + 'static) -> Result<(), Error> {
+        self.commands
+            .send(Box::new(command))
+            .map_err(|_| Error::Internal("Mixer thread has stopped".to_owned()))
+    }
+}
+
+// The device's output stream, and the queues that carry blocks of audio to its callback and bring
+// them back empty to be refilled. A fixed number of blocks go round, so neither side allocates.
+struct DeviceOutput {
+    _stream: Stream,
+    output_rate: u32,
+    filled_blocks: Sender<Vec<f32>>,
+    empty_blocks: Receiver<Vec<f32>>,
+}
+
+impl DeviceOutput {
+    fn open(output_latency: Arc<AtomicU64>) -> Result<Self, Error> {
+        let (filled_sender, filled_receiver) = crossbeam_channel::bounded(OUTPUT_BLOCK_COUNT);
+        let (empty_sender, empty_receiver) = crossbeam_channel::bounded(OUTPUT_BLOCK_COUNT);
+        for _ in 0..OUTPUT_BLOCK_COUNT {
+            let _ = empty_sender.send(vec![0.0; OUTPUT_BLOCK_FRAMES * CHANNEL_COUNT]);
+        }
+        let reader = BlockReader::new(filled_receiver, empty_sender);
+        let (stream, output_rate) = open_output_stream(reader, output_latency)?;
+        Ok(Self {
+            _stream: stream,
+            output_rate,
+            filled_blocks: filled_sender,
+            empty_blocks: empty_receiver,
+        })
+    }
+
+    // Refill blocks as the stream hands them back, running commands in between, until every
+    // handle to the thread has been dropped
+    fn run(self, mixer: &mut Mixer, commands: &Receiver<MixerCommand>) {
+        loop {
+            crossbeam_channel::select! {
+                recv(commands) -> command => match command {
+                    Ok(command) => command(mixer),
+                    Err(_) => return,
+                },
+                recv(self.empty_blocks) -> block => {
+                    let Ok(mut block) = block else {
+                        return;
+                    };
+                    mixer.render_at_rate(&mut block, self.output_rate);
+                    let _ = self.filled_blocks.send(block);
+                }
+            }
+        }
+    }
+}
+
+// The output stream's end of the block queues. Frames are copied out of the current block, which
+// is handed back once used up. If the mixer thread falls behind, silence is played rather than
+// waiting for it.
+struct BlockReader {
+    filled_blocks: Receiver<Vec<f32>>,
+    empty_blocks: Sender<Vec<f32>>,
+    block: Option<Vec<f32>>,
+    read_position: usize,
+}
+
+impl BlockReader {
+    fn new(filled_blocks: Receiver<Vec<f32>>, empty_blocks: Sender<Vec<f32>>) -> Self {
+        Self {
+            filled_blocks,
+            empty_blocks,
+            block: None,
+            read_position: 0,
+        }
+    }
+
+    fn read(&mut self, stereo: &mut [f32]) {
+        let mut written = 0;
+        while written < stereo.len() {
+            if self.block.is_none() {
+                match self.filled_blocks.try_recv() {
+                    Ok(block) => {
+                        self.block = Some(block);
+                        self.read_position = 0;
+                    }
+                    Err(_) => {
+                        stereo[written..].fill(0.0);
+                        return;
+                    }
+                }
+            }
+            let Some(block) = self.block.as_ref() else {
+                return;
+            };
+            let count = (block.len() - self.read_position).min(stereo.len() - written);
+            stereo[written..written + count]
+                .copy_from_slice(&block[self.read_position..self.read_position + count]);
+            written += count;
+            self.read_position += count;
+            if self.read_position == block.len()
+                && let Some(block) = self.block.take()
+            {
+                let _ = self.empty_blocks.try_send(block);
+            }
+        }
+    }
+
+    // Samples rendered by the mixer that are yet to be read
+    fn queued_samples(&self) -> usize {
+        let block_remainder = self
+            .block
+            .as_ref()
+            .map_or(0, |block| block.len() - self.read_position);
+        self.filled_blocks.len() * OUTPUT_BLOCK_FRAMES * CHANNEL_COUNT + block_remainder
+    }
+}
+
+// Open the default output device in its preferred format, returning the stream and its sample
+// rate. The mixer renders stereo, which is resampled to the device's rate on the mixer thread and
+// spread over the device's channels here.
+fn open_output_stream(
+    reader: BlockReader,
+    output_latency: Arc<AtomicU64>,
+) -> Result<(Stream, u32), Error> {
+    let device = cpal::default_host()
+        .default_output_device()
+        .ok_or_else(|| Error::User("No audio output device is available".to_owned()))?;
+    let supported_config = device
+        .default_output_config()
+        .map_err(|err| Error::User(format!("Cannot read output device config: {}", err)))?;
+    let config = supported_config.config();
+    let stream = match supported_config.sample_format() {
+        SampleFormat::I8 => build_output_stream::<i8>(&device, &config, reader, output_latency),
+        SampleFormat::I16 => build_output_stream::<i16>(&device, &config, reader, output_latency),
+        SampleFormat::I24 => build_output_stream::<I24>(&device, &config, reader, output_latency),
+        SampleFormat::I32 => build_output_stream::<i32>(&device, &config, reader, output_latency),
+        SampleFormat::I64 => build_output_stream::<i64>(&device, &config, reader, output_latency),
+        SampleFormat::U8 => build_output_stream::<u8>(&device, &config, reader, output_latency),
+        SampleFormat::U16 => build_output_stream::<u16>(&device, &config, reader, output_latency),
+        SampleFormat::U32 => build_output_stream::<u32>(&device, &config, reader, output_latency),
+        SampleFormat::U64 => build_output_stream::<u64>(&device, &config, reader, output_latency),
+        SampleFormat::F32 => build_output_stream::<f32>(&device, &config, reader, output_latency),
+        SampleFormat::F64 => build_output_stream::<f64>(&device, &config, reader, output_latency),
+        sample_format => Err(Error::User(format!(
+            "Unsupported output sample format: {:?}",
+            sample_format
+        ))),
+    }?;
+    stream
+        .play()
+        .map_err(|err| Error::User(format!("Cannot start output stream: {}", err)))?;
+    Ok((stream, config.sample_rate.0))
+}
+
+// The output latency is measured on every callback: the newest frame the mixer has rendered
+// plays after the frames written now and those still queued, which the device starts playing
+// once its own delay from the callback has passed.
+fn build_output_stream<T: SizedSample + FromSample<f32>>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mut reader: BlockReader,
+    output_latency: Arc<AtomicU64>,
+) -> Result<Stream, Error> {
+    let channel_count = config.channels as usize;
+    let output_rate = config.sample_rate.0 as f64;
+    let mut stereo = vec![];
+    device
+        .build_output_stream(
+            config,
+            move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+                stereo.resize(data.len() / channel_count * CHANNEL_COUNT, 0.0);
+                reader.read(&mut stereo);
+                write_output_frames(data, channel_count, &stereo);
+                let timestamp = info.timestamp();
+                if let Some(device_delay) = timestamp.playback.duration_since(&timestamp.callback) {
+                    let unplayed_frames = (stereo.len() + reader.queued_samples()) / CHANNEL_COUNT;
+                    let latency = device_delay
+                        + Duration::from_secs_f64(unplayed_frames as f64 / output_rate);
+                    output_latency.store(latency.as_nanos() as u64, Ordering::Relaxed);
+                }
+            },
+            |err| bevy::log::error!("Audio output error: {}", err),
+            None,
+        )
+        .map_err(|err| Error::User(format!("Cannot open output stream: {}", err)))
+}
+
+// Spread interleaved stereo over the device's channels, converting to its sample type. Mono devices
+// get the two channels mixed down, and channels past the first two are left silent.
+fn write_output_frames<T: Sample + FromSample<f32>>(
+    output: &mut [T],
+    channel_count: usize,
+    stereo: &[f32],
+) {
+    for (frame, stereo_frame) in output
+        .chunks_mut(channel_count)
+        .zip(stereo.chunks(CHANNEL_COUNT))
+    {
+        for (channel, sample) in frame.iter_mut().enumerate() {
+            let value = match (channel_count, channel) {
+                (1, _) => (stereo_frame[0] + stereo_frame[1]) * 0.5,
+                (_, 0 | 1) => stereo_frame[channel],
+                _ => 0.0,
+            };
+            *sample = T::from_sample(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEREO: [f32; 4] = [0.5, -0.5, 1.0, 0.0];
+
+    #[test]
+    fn blocks_are_read_across_their_boundaries_and_handed_back() {
+        let (filled_sender, filled_receiver) = crossbeam_channel::bounded(2);
+        let (empty_sender, empty_receiver) = crossbeam_channel::bounded(2);
+        let mut reader = BlockReader::new(filled_receiver, empty_sender);
+        filled_sender.send(vec![1.0, 2.0, 3.0, 4.0]).unwrap();
+        filled_sender.send(vec![5.0, 6.0, 7.0, 8.0]).unwrap();
+        let mut stereo = [0.0; 6];
+        reader.read(&mut stereo);
+        assert_eq!(stereo, [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(reader.queued_samples(), 2);
+        assert_eq!(empty_receiver.try_recv(), Ok(vec![1.0, 2.0, 3.0, 4.0]));
+        assert!(empty_receiver.try_recv().is_err());
+        // Running out of blocks plays silence instead of waiting
+        reader.read(&mut stereo);
+        assert_eq!(stereo, [7.0, 8.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(empty_receiver.try_recv(), Ok(vec![5.0, 6.0, 7.0, 8.0]));
+    }
+
+    #[test]
+    fn stereo_devices_get_the_mix_as_it_is() {
+        let mut output = [0.0f32; 4];
+        write_output_frames(&mut output, 2, &STEREO);
+        assert_eq!(output, STEREO);
+    }
+
+    #[test]
+    fn mono_devices_get_both_channels_mixed_down() {
+        let mut output = [1.0f32; 2];
+        write_output_frames(&mut output, 1, &STEREO);
+        assert_eq!(output, [0.0, 0.5]);
+    }
+
+    #[test]
+    fn extra_channels_are_silent() {
+        let mut output = [1.0f32; 12];
+        write_output_frames(&mut output, 6, &STEREO);
+        assert_eq!(output[..6], [0.5, -0.5, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(output[6..], [1.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn samples_are_converted_to_the_device_format() {
+        let mut signed = [0i16; 4];
+        write_output_frames(&mut signed, 2, &STEREO);
+        assert_eq!(signed, [16384, -16384, i16::MAX, 0]);
+        // Unsigned formats are centred on half their range
+        let mut unsigned = [0u8; 4];
+        write_output_frames(&mut unsigned, 2, &STEREO);
+        assert_eq!(unsigned, [192, 64, u8::MAX, 128]);
+    }
+}
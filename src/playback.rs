@@ -0,0 +1,180 @@
+use crate::{
+    MidiFileSource, MidiGraph, MidiGraphAudioContext,
+    asset::graph::MidiNodeSource,
+    backend::{OfflineAudioOutput, VirtualTimeSync},
+    message::{MidiCueReached, MidiLooped, MidiNoteFired, MidiTrackEnded},
+    timeline::MidiTimeline,
+};
+use bevy::prelude::*;
+use midi_graph::{Error, MidiPlaybackState};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+// Musical position of a Midi node, read from the state snapshot serialized from its
+// MidiPlaybackState.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MidiPosition {
+    pub tick: u64,
+    pub ticks_per_beat: u32,
+    pub microseconds_per_beat: u32,
+    pub beats_per_bar: u32,
+}
+
+impl MidiPosition {
+    // Fails if the snapshot doesn't match midi-graph's MidiPlaybackState, which means the node
+    // isn't a Midi node or the two crates disagree on its layout.
+    pub fn from_snapshot(snapshot: Value) -> Result<Self, Error> {
+        let state = serde_json::from_value::<MidiPlaybackState>(snapshot).map_err(|err| {
+            Error::Internal(format!(
+                "Node state snapshot is not a MidiPlaybackState: {}",
+                err
+            ))
+        })?;
+        Ok(Self::from(&state))
+    }
+
+    // Whole beats since the start of the track
+    pub fn beat_index(&self) -> u64 {
+        self.tick / self.ticks_per_beat as u64
+    }
+
+    // Whole bars since the start of the track
+    pub fn bar_index(&self) -> u64 {
+        self.beat_index() / self.beats_per_bar.max(1) as u64
+    }
+}
+
+impl From<&MidiPlaybackState> for MidiPosition {
+    fn from(state: &MidiPlaybackState) -> Self {
+        Self {
+            tick: state.tick as u64,
+            ticks_per_beat: state.ticks_per_beat as u32,
+            microseconds_per_beat: state.microseconds_per_beat as u32,
+            beats_per_bar: state.beats_per_bar as u32,
+        }
+    }
+}
+
+// Follows the Midi nodes of the playing program from frame to frame to produce playback
+// notifications.
+#[derive(Resource, Default)]
+pub struct PlaybackTracker {
+    timelines: HashMap<(AssetId<MidiFileSource>, usize), Option<Arc<MidiTimeline>>>,
+    program_no: Option<usize>,
+    last_ticks: HashMap<u64, u64>,
+    ended_nodes: HashSet<u64>,
+}
+
+impl PlaybackTracker {
+    pub fn emit_playback_notifications(
+        mut tracker: ResMut<PlaybackTracker>,
+        audio_context: Res<MidiGraphAudioContext>,
+        real_time: Res<Time<Real>>,
+        virtual_time: Res<Time<Virtual>>,
+        sync: Res<VirtualTimeSync>,
+        offline_output: Option<Res<OfflineAudioOutput>>,
+        asset_server: Res<AssetServer>,
+        graphs: Res<Assets<MidiGraph>>,
+        midi_assets: Res<Assets<MidiFileSource>>,
+        mut midi_asset_events: MessageReader<AssetEvent<MidiFileSource>>,
+        mut notes_fired: MessageWriter<MidiNoteFired>,
+        mut cues_reached: MessageWriter<MidiCueReached>,
+        mut loops: MessageWriter<MidiLooped>,
+        mut tracks_ended: MessageWriter<MidiTrackEnded>,
+    ) {
+        for event in midi_asset_events.read() {
+            if let AssetEvent::Modified { id } = event {
+                tracker.timelines.retain(|(asset_id, _), _| asset_id != id);
+            }
+        }
+        let playing_program = audio_context.playing_program();
+        if tracker.program_no != playing_program {
+            tracker.program_no = playing_program;
+            tracker.last_ticks.clear();
+            tracker.ended_nodes.clear();
+        }
+        let Some(graph) = playing_program
+            .and_then(|program_no| audio_context.program_asset(program_no))
+            .and_then(|asset_handle| graphs.get(asset_handle))
+        else {
+            return;
+        };
+        // Audio played since the last frame, following how the backend renders it
+        let rendered_seconds = match offline_output.and_then(|output| output.frames_per_update) {
+            Some(frame_count) => frame_count as f64 / OfflineAudioOutput::SAMPLE_RATE as f64,
+            None => real_time.delta_secs_f64(),
+        };
+        let elapsed_seconds = rendered_seconds * sync.playback_rate(&virtual_time) as f64;
+        for (node_id, source) in graph.midi_sources.iter() {
+            let node_id = *node_id;
+            let Some(position) = audio_context.capture_midi_position(node_id) else {
+                continue;
+            };
+            let Some(timeline) = tracker.timeline(&asset_server, &midi_assets, source) else {
+                continue;
+            };
+            let Some(previous_tick) = tracker.last_ticks.insert(node_id, position.tick) else {
+                continue;
+            };
+            if timeline.is_jump(previous_tick, position.tick, elapsed_seconds) {
+                // Where the jump landed isn't known exactly, so the notes and cues skipped over
+                // or around it are not reported
+                if position.tick < previous_tick {
+                    tracker.ended_nodes.remove(&node_id);
+                }
+                loops.write(MidiLooped {
+                    node_id,
+                    position,
+                    from_tick: previous_tick,
+                });
+                continue;
+            }
+            for note in timeline.notes_between(previous_tick, position.tick) {
+                notes_fired.write(MidiNoteFired {
+                    node_id,
+                    position,
+                    channel: note.channel,
+                    note: note.note,
+                    velocity: note.velocity,
+                });
+            }
+            for cue in timeline.cues_between(previous_tick, position.tick) {
+                cues_reached.write(MidiCueReached {
+                    node_id,
+                    position,
+                    label: cue.label.clone(),
+                });
+            }
+            if position.tick >= timeline.length_ticks && tracker.ended_nodes.insert(node_id) {
+                tracks_ended.write(MidiTrackEnded { node_id, position });
+            }
+        }
+    }
+
+    // Parse a Midi node's track the first time it is needed. Failures are remembered so that a
+    // bad file isn't parsed again every frame.
+    pub(crate) fn timeline(
+        &mut self,
+        asset_server: &AssetServer,
+        midi_assets: &Assets<MidiFileSource>,
+        source: &MidiNodeSource,
+    ) -> Option<Arc<MidiTimeline>> {
+        let asset_handle = asset_server.get_handle::<MidiFileSource>(source.path.as_str())?;
+        let key = (asset_handle.id(), source.track_index);
+        if let Some(timeline) = self.timelines.get(&key) {
+            return timeline.clone();
+        }
+        let asset = midi_assets.get(&asset_handle)?;
+        let timeline = asset
+            .data
+            .lock()
+            .ok()
+            .and_then(|data| MidiTimeline::parse(&data, source.track_index).ok())
+            .map(Arc::new);
+        self.timelines.insert(key, timeline.clone());
+        timeline
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{
+    GraphAssetLoader, MidiFileSource, MidiGraph, MidiGraphAudioContext, NodeRef, Sf2FileSource,
+    WaveFileSource,
+    message::{MidiGraphCommand, MidiGraphPlayerFailed},
+    mixer_thread::MixerThread,
+};
+use bevy::{asset::RecursiveDependencyLoadState, prelude::*};
+use midi_graph::{AssetLoader, Error, EventTarget, MessageSender};
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
+
+/// Plays a graph for as long as the entity exists, mixed into the same output as the programs in
+/// [`MidiGraphAudioContext`] but independently of them. Playback starts once the graph and its
+/// sub-assets have loaded, and stops when the component is removed or the entity is despawned.
+#[derive(Component, Clone, Debug)]
+pub struct MidiGraphPlayer {
+    pub graph: Handle<MidiGraph>,
+}
+
+impl MidiGraphPlayer {
+    pub fn new(graph: Handle<MidiGraph>) -> Self {
+        Self { graph }
+    }
+}
+
+struct PlayerInstance {
+    event_sender: Arc<MessageSender>,
+    graph: AssetId<MidiGraph>,
+    // Names are resolved against the graph the player was started with
+    node_names: HashMap<String, u64>,
+}
+
+/// The graphs of all playing [`MidiGraphPlayer`] entities, which share the audio context's mixer.
+#[derive(Resource)]
+pub struct MidiGraphPlayers {
+    mixer: MixerThread,
+    instances: HashMap<Entity, PlayerInstance>,
+    // Players whose graph failed, so they aren't retried every frame
+    failed: HashMap<Entity, AssetId<MidiGraph>>,
+}
+
+impl MidiGraphPlayers {
+    pub(crate) fn new(mixer: MixerThread) -> Self {
+        Self {
+            mixer,
+            instances: HashMap::new(),
+            failed: HashMap::new(),
+        }
+    }
+
+    pub fn start_players(
+        mut players: ResMut<MidiGraphPlayers>,
+        player_query: Query<(Entity, &MidiGraphPlayer)>,
+        mut player_failures: MessageWriter<MidiGraphPlayerFailed>,
+        asset_server: Res<AssetServer>,
+        graphs: Res<Assets<MidiGraph>>,
+        midi_assets: Res<Assets<MidiFileSource>>,
+        sf2_assets: Res<Assets<Sf2FileSource>>,
+        wave_assets: Res<Assets<WaveFileSource>>,
+    ) -> Result<(), BevyError> {
+        let mut loader =
+            GraphAssetLoader::new(&asset_server, &midi_assets, &sf2_assets, &wave_assets);
+        for (entity, player) in player_query.iter() {
+            let graph_id = player.graph.id();
+            // The graph handle may have been replaced since the player started
+            if let Some(instance) = players.instances.get(&entity) {
+                if instance.graph == graph_id {
+                    continue;
+                }
+                players.stop_instance(entity)?;
+            }
+            if players.failed.get(&entity) == Some(&graph_id) {
+                continue;
+            }
+            let result = match asset_server.recursive_dependency_load_state(&player.graph) {
+                RecursiveDependencyLoadState::Loaded => {
+                    let asset = graphs.get(&player.graph).unwrap();
+                    players
+                        .start_instance(entity, graph_id, asset, &mut loader)
+                        .map_err(|error| {
+                            let asset_path = asset_server
+                                .get_path(&player.graph)
+                                .map(|path| path.into_owned());
+                            (asset_path, error)
+                        })
+                }
+                RecursiveDependencyLoadState::Failed(load_error) => {
+                    let asset_path = MidiGraphAudioContext::find_failed_asset_path(
+                        &asset_server,
+                        &graphs,
+                        &player.graph,
+                    );
+                    let error = Error::User(format!("Asset failed to load: {}", load_error));
+                    Err((asset_path, error))
+                }
+                _ => continue,
+            };
+            if let Err((asset_path, error)) = result {
+                players.failed.insert(entity, graph_id);
+                player_failures.write(MidiGraphPlayerFailed {
+                    entity,
+                    asset_path,
+                    error,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stop_removed_players(
+        mut players: ResMut<MidiGraphPlayers>,
+        mut removed_players: RemovedComponents<MidiGraphPlayer>,
+    ) -> Result<(), BevyError> {
+        for entity in removed_players.read() {
+            players.stop_instance(entity)?;
+            players.failed.remove(&entity);
+        }
+        Ok(())
+    }
+
+    fn start_instance(
+        &mut self,
+        entity: Entity,
+        graph_id: AssetId<MidiGraph>,
+        graph: &MidiGraph,
+        loader: &mut dyn AssetLoader,
+    ) -> Result<(), Error> {
+        let node = graph.config.0.to_node(loader)?;
+        let event_sender = self
+            .mixer
+            .run(move |mixer| mixer.add_player(entity, node))?;
+        self.instances.insert(
+            entity,
+            PlayerInstance {
+                event_sender,
+                graph: graph_id,
+                node_names: graph.node_names.clone(),
+            },
+        );
+        Ok(())
+    }
+
+    fn stop_instance(&mut self, entity: Entity) -> Result<(), Error> {
+        if self.instances.remove(&entity).is_some() {
+            // The graph is dropped here, where freeing its samples doesn't hold up rendering
+            let _node = self.mixer.run(move |mixer| mixer.remove_player(entity))?;
+        }
+        Ok(())
+    }
+
+    pub fn is_playing(&self, entity: Entity) -> bool {
+        self.instances.contains_key(&entity)
+    }
+
+    // Sender for events to the graph playing for an entity
+    pub fn get_event_sender(&self, entity: Entity) -> Option<Arc<MessageSender>> {
+        self.instances
+            .get(&entity)
+            .map(|instance| instance.event_sender.clone())
+    }
+
+    // Node id of a node in the graph playing for an entity, given by id or name
+    pub fn node_id(&self, entity: Entity, node: &NodeRef) -> Option<u64> {
+        let instance = self.instances.get(&entity)?;
+        match node {
+            NodeRef::Id(node_id) => Some(*node_id),
+            NodeRef::Name(name) => instance.node_names.get(name).copied(),
+        }
+    }
+
+    // Send a command, such as one built from a node handle, to the graph playing for an entity.
+    // A command for a named node fails if the entity's graph has no node with that name, and one
+    // tied to another graph fails too.
+    pub fn send(&self, entity: Entity, mut command: MidiGraphCommand) -> Result<(), Error> {
+        let instance = self
+            .instances
+            .get(&entity)
+            .ok_or_else(|| Error::User(format!("No graph is playing for entity {}", entity)))?;
+        if let Some(graph) = command.graph
+            && graph != instance.graph
+        {
+            return Err(Error::User(format!(
+                "Command for graph {:?} sent to entity {}, which is playing graph {:?}",
+                graph, entity, instance.graph
+            )));
+        }
+        if let Some(node_name) = command.node_name.take() {
+            let node_id = instance.node_names.get(&node_name).ok_or_else(|| {
+                Error::User(format!(
+                    "No node named \"{}\" in the graph playing for entity {}",
+                    node_name, entity
+                ))
+            })?;
+            command.target = EventTarget::SpecificNode(*node_id);
+        }
+        instance
+            .event_sender
+            .send(command.into_message())
+            .map_err(|err| Error::User(format!("Could not send event to player: {:?}", err)))
+    }
+
+    // Set the gain and pan, from -1 (left) to 1 (right), applied to the output of an entity's
+    // graph. Gain and pan are applied by the mixer, so they never overwrite the volumes of the
+    // graph's own nodes.
+    pub fn set_gain_and_pan(&self, entity: Entity, gain: f32, pan: f32) -> Result<(), Error> {
+        if !self.is_playing(entity) {
+            return Err(Error::User(format!(
+                "No graph is playing for entity {}",
+                entity
+            )));
+        }
+        self.mixer
+            .post(move |mixer| mixer.set_player_gain_and_pan(entity, gain, pan))
+    }
+
+    pub fn capture_node_state(&self, entity: Entity, node_id: u64) -> Option<Result<Value, Error>> {
+        if !self.is_playing(entity) {
+            return None;
+        }
+        self.mixer
+            .run(move |mixer| mixer.get_player_node_state_snapshot(entity, node_id))
+            .unwrap_or_else(|err| Some(Err(err)))
+    }
+}
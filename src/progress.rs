@@ -0,0 +1,118 @@
+use crate::{MidiFileSource, MidiGraph, MidiGraphAudioContext, Sf2FileSource, WaveFileSource};
+use bevy::{asset::UntypedAssetId, prelude::*};
+use std::{collections::HashMap, sync::Mutex};
+
+/// How far a pending program has got with loading its graph and the files it depends on.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoadProgress {
+    /// Assets loaded so far, counting the graph itself.
+    pub loaded_assets: usize,
+    /// Assets to load, counting the graph itself. Until the graph has loaded, its dependencies
+    /// aren't known, so this is just one.
+    pub total_assets: usize,
+    /// Size of the sub-asset files loaded so far. The total size is only known once every file
+    /// has loaded.
+    pub loaded_bytes: u64,
+}
+
+impl LoadProgress {
+    // Fraction of assets loaded, from 0 to 1
+    pub fn fraction(&self) -> f32 {
+        if self.total_assets == 0 {
+            return 0.0;
+        }
+        self.loaded_assets as f32 / self.total_assets as f32
+    }
+}
+
+/// Loading progress of every program still being loaded, updated each frame for loading
+/// screens.
+#[derive(Resource, Default)]
+pub struct ProgramLoadProgress {
+    programs: HashMap<usize, LoadProgress>,
+}
+
+impl ProgramLoadProgress {
+    pub fn get(&self, program_no: usize) -> Option<&LoadProgress> {
+        self.programs.get(&program_no)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &LoadProgress)> {
+        self.programs
+            .iter()
+            .map(|(program_no, progress)| (*program_no, progress))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.programs.is_empty()
+    }
+
+    // Progress of all pending programs together
+    pub fn combined(&self) -> LoadProgress {
+        self.programs
+            .values()
+            .fold(LoadProgress::default(), |combined, progress| LoadProgress {
+                loaded_assets: combined.loaded_assets + progress.loaded_assets,
+                total_assets: combined.total_assets + progress.total_assets,
+                loaded_bytes: combined.loaded_bytes + progress.loaded_bytes,
+            })
+    }
+
+    pub fn update_progress(
+        mut load_progress: ResMut<ProgramLoadProgress>,
+        audio_context: Res<MidiGraphAudioContext>,
+        asset_server: Res<AssetServer>,
+        graphs: Res<Assets<MidiGraph>>,
+        midi_assets: Res<Assets<MidiFileSource>>,
+        sf2_assets: Res<Assets<Sf2FileSource>>,
+        wave_assets: Res<Assets<WaveFileSource>>,
+    ) {
+        load_progress.programs.clear();
+        for (program_no, asset_handle) in audio_context.loading_programs() {
+            let Some(graph) = graphs.get(asset_handle) else {
+                load_progress.programs.insert(
+                    program_no,
+                    LoadProgress {
+                        loaded_assets: 0,
+                        total_assets: 1,
+                        loaded_bytes: 0,
+                    },
+                );
+                continue;
+            };
+            let mut progress = LoadProgress {
+                loaded_assets: 1,
+                total_assets: 1,
+                loaded_bytes: 0,
+            };
+            let mut dependencies: Vec<(UntypedAssetId, Option<u64>)> = vec![];
+            dependencies.extend(graph.midi_assets.iter().map(|handle| {
+                let bytes = midi_assets.get(handle).map(|asset| data_len(&asset.data));
+                (handle.id().untyped(), bytes)
+            }));
+            dependencies.extend(graph.sf2_assets.iter().map(|handle| {
+                let bytes = sf2_assets.get(handle).map(|asset| data_len(&asset.data));
+                (handle.id().untyped(), bytes)
+            }));
+            dependencies.extend(graph.wave_assets.iter().map(|handle| {
+                let bytes = wave_assets.get(handle).map(|asset| data_len(&asset.data));
+                (handle.id().untyped(), bytes)
+            }));
+            // The same file may be used by several nodes
+            dependencies.sort_by_key(|(id, _)| *id);
+            dependencies.dedup_by_key(|(id, _)| *id);
+            for (id, bytes) in dependencies {
+                progress.total_assets += 1;
+                if asset_server.is_loaded(id) {
+                    progress.loaded_assets += 1;
+                    progress.loaded_bytes += bytes.unwrap_or(0);
+                }
+            }
+            load_progress.programs.insert(program_no, progress);
+        }
+    }
+}
+
+fn data_len(data: &Mutex<Vec<u8>>) -> u64 {
+    data.lock().map(|data| data.len() as u64).unwrap_or(0)
+}
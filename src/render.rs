@@ -0,0 +1,195 @@
+use crate::{
+    AssetType, GraphAssetLoader, MidiGraph,
+    asset::{
+        graph::{MidiGraphLoaderSettings, load_graph_file},
+        include::{GraphReader, ReadFuture},
+    },
+    backend::{CHANNEL_COUNT, Mixer, SAMPLE_RATE},
+    timeline::MidiTimeline,
+};
+use midi_graph::{
+    AssetLoadPayload, AssetLoader, Error, SampleBuffer, SerializedFileMetadata,
+    abstraction::ChildConfig,
+};
+use std::path::{Path, PathBuf};
+
+const RENDER_PROGRAM_NO: usize = 0;
+const RENDER_CHUNK_FRAMES: usize = 1024;
+// Rendered after the MIDI tracks end, so the last notes can release
+const RELEASE_TAIL_SECONDS: f64 = 1.0;
+
+/// How much audio to render when writing a graph to a file.
+#[derive(Clone, Copy, Debug)]
+pub enum RenderLength {
+    Seconds(f32),
+    /// Render until the longest MIDI track used by the graph has played through once, plus a
+    /// second for its last notes to release, but never for longer than the given number of
+    /// seconds. Graphs without MIDI files always run to the limit.
+    UntilTrackEnds {
+        max_seconds: f32,
+    },
+}
+
+// Resolves graph sub-assets directly from files, relative to an asset root directory, for use
+// outside of a running Bevy app.
+pub struct FileAssetLoader {
+    asset_root: PathBuf,
+}
+
+impl FileAssetLoader {
+    pub fn new(asset_root: impl Into<PathBuf>) -> Self {
+        Self {
+            asset_root: asset_root.into(),
+        }
+    }
+}
+
+impl AssetLoader for FileAssetLoader {
+    fn load_asset_data(&mut self, path: &str) -> Result<AssetLoadPayload, Error> {
+        GraphAssetLoader::infer_asset_type(path)?;
+        let bytes = std::fs::read(self.asset_root.join(path))?;
+        Ok(AssetLoadPayload::RawAssetData(bytes))
+    }
+
+    fn store_prepared_data(
+        &mut self,
+        _path: &str,
+        _metadata: SerializedFileMetadata,
+        _sample_buffer: SampleBuffer,
+    ) {
+        // Each render builds its graph once, so there is nothing worth caching
+    }
+}
+
+impl GraphReader for FileAssetLoader {
+    fn read<'a>(&'a mut self, path: &'a str) -> ReadFuture<'a> {
+        let result = std::fs::read(self.asset_root.join(path)).map_err(|err| err.to_string());
+        Box::pin(async move { result })
+    }
+}
+
+// Render a graph config without an audio device and write the result to a WAV file.
+pub fn render_config_to_wav(
+    config: &ChildConfig,
+    loader: &mut dyn AssetLoader,
+    length: RenderLength,
+    output_path: &Path,
+) -> Result<(), Error> {
+    let samples = render_config(config, loader, length)?;
+    write_wav(&samples, output_path)
+}
+
+// Render a loaded MidiGraph asset; its sub-assets are resolved through the given loader, which
+// inside an app would be a GraphAssetLoader.
+pub fn render_graph_to_wav(
+    graph: &MidiGraph,
+    loader: &mut dyn AssetLoader,
+    length: RenderLength,
+    output_path: &Path,
+) -> Result<(), Error> {
+    render_config_to_wav(&graph.config, loader, length, output_path)
+}
+
+// Render a graph file; the graph path and the paths inside it are relative to the asset root.
+// The file is loaded the same way as by the asset loaders, so it may be in any enabled format,
+// include other graph files and name its nodes, and is validated before it is built.
+pub fn render_graph_file_to_wav(
+    asset_root: &Path,
+    graph_path: &str,
+    length: RenderLength,
+    output_path: &Path,
+) -> Result<(), Error> {
+    let mut loader = FileAssetLoader::new(asset_root);
+    let graph = load_graph_file(graph_path, &mut loader, &MidiGraphLoaderSettings::default())
+        .map_err(|err| Error::User(format!("Cannot load graph {}: {}", graph_path, err)))?;
+    render_graph_to_wav(&graph, &mut loader, length, output_path)
+}
+
+// Render a graph config to interleaved samples, at the rate and channel count of
+// OfflineAudioOutput.
+pub fn render_config(
+    config: &ChildConfig,
+    loader: &mut dyn AssetLoader,
+    length: RenderLength,
+) -> Result<Vec<f32>, Error> {
+    let channel_count = CHANNEL_COUNT;
+    let sample_rate = SAMPLE_RATE as f64;
+    let mut mixer = Mixer::new();
+    let node = config.0.to_node(loader)?;
+    mixer.store_program(RENDER_PROGRAM_NO, node);
+    mixer.change_program(RENDER_PROGRAM_NO)?;
+
+    let seconds = match length {
+        RenderLength::Seconds(seconds) => seconds as f64,
+        RenderLength::UntilTrackEnds { max_seconds } => {
+            match midi_length_seconds(config, loader)? {
+                Some(track_seconds) => {
+                    (track_seconds + RELEASE_TAIL_SECONDS).min(max_seconds as f64)
+                }
+                None => max_seconds as f64,
+            }
+        }
+    };
+    let total_frames = (seconds * sample_rate) as usize;
+    let mut samples = Vec::with_capacity(total_frames * channel_count);
+    let mut buffer = vec![0.0; RENDER_CHUNK_FRAMES * channel_count];
+    let mut rendered_frames = 0;
+    while rendered_frames < total_frames {
+        let chunk_frames = RENDER_CHUNK_FRAMES.min(total_frames - rendered_frames);
+        let chunk = &mut buffer[..chunk_frames * channel_count];
+        mixer.render(chunk);
+        samples.extend_from_slice(chunk);
+        rendered_frames += chunk_frames;
+    }
+    Ok(samples)
+}
+
+// Play time of the longest track in any MIDI file used by a graph, following its tempo changes,
+// or None if the graph uses no MIDI files
+fn midi_length_seconds(
+    config: &ChildConfig,
+    loader: &mut dyn AssetLoader,
+) -> Result<Option<f64>, Error> {
+    let mut midi_paths = vec![];
+    ChildConfig::traverse_config_tree(config, &mut |config: &ChildConfig| {
+        if let Some(path) = config.0.asset_source()
+            && let Ok(AssetType::Midi) = GraphAssetLoader::infer_asset_type(path)
+        {
+            midi_paths.push(path.to_owned());
+        }
+    });
+    let mut length: Option<f64> = None;
+    for path in midi_paths {
+        let bytes = match loader.load_asset_data(&path)? {
+            AssetLoadPayload::RawAssetData(bytes) => bytes,
+            _ => continue,
+        };
+        for track_index in 0..MidiTimeline::track_count(&bytes)? {
+            let timeline = MidiTimeline::parse(&bytes, track_index)?;
+            let track_seconds = timeline.seconds_at(timeline.length_ticks);
+            length = Some(length.map_or(track_seconds, |length| length.max(track_seconds)));
+        }
+    }
+    Ok(length)
+}
+
+// Write interleaved samples, as rendered by render_config or OfflineAudioOutput, to a WAV file.
+pub fn write_wav(samples: &[f32], output_path: &Path) -> Result<(), Error> {
+    let spec = hound::WavSpec {
+        channels: CHANNEL_COUNT as u16,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(output_path, spec)
+        .map_err(|e| Error::User(format!("Cannot create WAV file: {:?}", e)))?;
+    for sample in samples {
+        writer
+            .write_sample(*sample)
+            .map_err(|e| Error::User(format!("Cannot write WAV sample: {:?}", e)))?;
+    }
+    writer
+        .finalize()
+        .map_err(|e| Error::User(format!("Cannot finish WAV file: {:?}", e)))?;
+    Ok(())
+}
@@ -1,83 +1,369 @@
 use crate::{
     GraphAssetLoader, MidiFileSource, MidiGraph, Sf2FileSource, WaveFileSource,
+    backend::{self, AudioBackend, DeckSlot, OfflineAudioOutput, VirtualTimeSync},
+    clock::MusicClock,
+    message::{MidiGraphCommand, MidiGraphCommandFailed, ProgramLoadFailed, ProgramReady},
+    mixer_thread::MixerThread,
+    playback::MidiPosition,
     state::AudioContextState,
+    transition::{ActiveTransition, ProgramTransition, TransitionStage},
+};
+use bevy::{
+    asset::{AssetPath, LoadState, RecursiveDependencyLoadState, UntypedAssetId},
+    prelude::*,
+    tasks::{AsyncComputeTaskPool, Task, futures::check_ready},
+};
+use midi_graph::{
+    AssetLoader, Error, EventTarget, GraphNode, MessageSender, abstraction::ChildConfig,
 };
-use bevy::prelude::*;
-use midi_graph::{AssetLoader, BaseMixer, Error, MessageSender, abstraction::ChildConfig};
 use serde_json::Value;
-use std::sync::{Arc, Mutex};
-
-pub struct SendMixer(BaseMixer);
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
-unsafe impl Send for SendMixer {}
+// Rebuilt programs that fell further behind than this while being fast-forwarded are caught up off
+// the main thread again, rather than while swapping them in
+const INLINE_CATCH_UP_SECONDS: f64 = 0.1;
 
 #[derive(Resource)]
 pub struct MidiGraphAudioContext {
-    mixer: Mutex<SendMixer>,
+    mixer: MixerThread,
     event_sender: Arc<MessageSender>,
-    playing_program: Option<(usize, Handle<MidiGraph>)>,
-    loading_program: Option<(usize, Handle<MidiGraph>)>,
+    playing_program: Option<usize>,
+    // The program most recently requested with start_new_program, to switch to once loaded
+    requested_program: Option<(usize, ProgramTransition)>,
+    transition: Option<ActiveTransition>,
+    loading_programs: HashMap<usize, Handle<MidiGraph>>,
+    stored_programs: HashMap<usize, Handle<MidiGraph>>,
+    rebuilding_programs: HashMap<usize, ProgramRebuild>,
+}
+
+// A program rebuilt after a change on disk, being fast-forwarded off the main thread while its
+// previous build keeps playing
+struct ProgramRebuild {
+    asset_handle: Handle<MidiGraph>,
+    // Time from the start of the track that the rebuilt program is being fast-forwarded to
+    resume_seconds: f64,
+    task: Task<GraphNode>,
 }
 
-impl Default for MidiGraphAudioContext {
-    fn default() -> Self {
-        let mixer = BaseMixer::builder_with_default_registry()
-            .unwrap()
-            .start(None)
-            .unwrap();
-        let event_sender = mixer.get_event_sender();
-        Self {
-            mixer: Mutex::new(SendMixer(mixer)),
+fn spawn_fast_forward(mut node: GraphNode, seconds: f64) -> Task<GraphNode> {
+    AsyncComputeTaskPool::get().spawn(async move {
+        backend::fast_forward(&mut node, seconds);
+        node
+    })
+}
+
+impl MidiGraphAudioContext {
+    pub fn new(backend: &AudioBackend) -> Result<Self, Error> {
+        let mixer = MixerThread::start(backend)?;
+        let event_sender = mixer.run(|mixer| mixer.get_event_sender())?;
+        Ok(Self {
+            mixer,
             event_sender,
             playing_program: None,
-            loading_program: None,
-        }
+            requested_program: None,
+            transition: None,
+            loading_programs: HashMap::new(),
+            stored_programs: HashMap::new(),
+            rebuilding_programs: HashMap::new(),
+        })
+    }
+
+    pub fn has_loading_programs(audio_context: Res<MidiGraphAudioContext>) -> bool {
+        !audio_context.loading_programs.is_empty()
     }
-}
 
-impl MidiGraphAudioContext {
     pub fn check_loading_asset(
         server: Res<AssetServer>,
         mut audio_context: ResMut<MidiGraphAudioContext>,
         mut next_state: ResMut<NextState<AudioContextState>>,
+        mut ready_programs: MessageWriter<ProgramReady>,
+        mut load_failures: MessageWriter<ProgramLoadFailed>,
         asset_server: Res<AssetServer>,
         graphs: ResMut<Assets<MidiGraph>>,
         midi_assets: Res<Assets<MidiFileSource>>,
         sf2_assets: Res<Assets<Sf2FileSource>>,
         wave_assets: Res<Assets<WaveFileSource>>,
     ) -> Result<(), BevyError> {
-        let (loading_program_no, loading_asset_handle) = match &audio_context.loading_program {
-            Some((program_no, asset_handle)) => (*program_no, asset_handle.clone()),
-            None => {
-                return Err(Error::User(
-                    "Internal error: checking loading state with no asset".to_owned(),
-                )
-                .into());
+        let mut loader =
+            GraphAssetLoader::new(&asset_server, &midi_assets, &sf2_assets, &wave_assets);
+        let loading_programs: Vec<(usize, Handle<MidiGraph>)> = audio_context
+            .loading_programs
+            .iter()
+            .map(|(program_no, asset_handle)| (*program_no, asset_handle.clone()))
+            .collect();
+        for (program_no, asset_handle) in loading_programs {
+            let switch_transition = audio_context
+                .requested_program
+                .filter(|(requested_program_no, _)| *requested_program_no == program_no)
+                .map(|(_, transition)| transition);
+            let switch_to_program = switch_transition.is_some();
+            let result = match server.recursive_dependency_load_state(&asset_handle) {
+                RecursiveDependencyLoadState::Loaded => {
+                    let asset = graphs.get(&asset_handle).unwrap();
+                    audio_context
+                        .finish_loading_program(
+                            program_no,
+                            asset_handle.clone(),
+                            &asset.config,
+                            &mut loader,
+                            switch_transition,
+                        )
+                        .map_err(|error| {
+                            let asset_path =
+                                server.get_path(&asset_handle).map(|path| path.into_owned());
+                            (asset_path, error)
+                        })
+                }
+                RecursiveDependencyLoadState::Failed(load_error) => {
+                    let asset_path = Self::find_failed_asset_path(&server, &graphs, &asset_handle);
+                    let error = Error::User(format!("Asset failed to load: {}", load_error));
+                    Err((asset_path, error))
+                }
+                RecursiveDependencyLoadState::NotLoaded | RecursiveDependencyLoadState::Loading => {
+                    continue;
+                }
+            };
+            audio_context.loading_programs.remove(&program_no);
+            if switch_to_program {
+                audio_context.requested_program = None;
+            }
+            match result {
+                Ok(()) => {
+                    ready_programs.write(ProgramReady { program_no });
+                    if switch_to_program {
+                        next_state.set(AudioContextState::Running);
+                    }
+                }
+                Err((asset_path, error)) => {
+                    if switch_to_program {
+                        next_state.set(AudioContextState::Failed);
+                    }
+                    load_failures.write(ProgramLoadFailed {
+                        program_no,
+                        asset_path,
+                        error,
+                    });
+                }
             }
-        };
-        if !server.is_loaded_with_dependencies(&loading_asset_handle) {
-            return Ok(());
         }
-        next_state.set(AudioContextState::Running);
+        Ok(())
+    }
+
+    // Rebuild stored programs when their graph or any of its sub-assets changes on disk, swapping
+    // in the rebuilt program if it is playing. This requires Bevy's asset hot reloading (the
+    // file_watcher feature) to be enabled.
+    //
+    // midi-graph has no event to seek a Midi node to a tick, so a rebuilt program that replaces
+    // the playing one is fast-forwarded to the time the MusicClock last read from it, which
+    // reaches the same tick unless playback had jumped to a cue. Programs the clock can't follow
+    // start from the beginning. Fast-forwarding renders the whole way there, so it runs on the
+    // async compute pool, and finish_rebuilt_programs swaps the program in once it is done.
+    pub fn reload_modified_programs(
+        mut audio_context: ResMut<MidiGraphAudioContext>,
+        clock: Res<MusicClock>,
+        mut graph_events: MessageReader<AssetEvent<MidiGraph>>,
+        mut midi_events: MessageReader<AssetEvent<MidiFileSource>>,
+        mut sf2_events: MessageReader<AssetEvent<Sf2FileSource>>,
+        mut wave_events: MessageReader<AssetEvent<WaveFileSource>>,
+        mut load_failures: MessageWriter<ProgramLoadFailed>,
+        asset_server: Res<AssetServer>,
+        graphs: Res<Assets<MidiGraph>>,
+        midi_assets: Res<Assets<MidiFileSource>>,
+        sf2_assets: Res<Assets<Sf2FileSource>>,
+        wave_assets: Res<Assets<WaveFileSource>>,
+    ) {
+        let mut modified_ids: HashSet<UntypedAssetId> = HashSet::new();
+        modified_ids.extend(graph_events.read().filter_map(|event| match event {
+            AssetEvent::Modified { id } | AssetEvent::LoadedWithDependencies { id } => {
+                Some(id.untyped())
+            }
+            _ => None,
+        }));
+        modified_ids.extend(Self::modified_asset_ids(&mut midi_events));
+        modified_ids.extend(Self::modified_asset_ids(&mut sf2_events));
+        modified_ids.extend(Self::modified_asset_ids(&mut wave_events));
+        if modified_ids.is_empty() {
+            return;
+        }
+
+        let mut programs_to_reload: Vec<(usize, Handle<MidiGraph>)> = audio_context
+            .stored_programs
+            .iter()
+            .filter(|(program_no, _)| !audio_context.loading_programs.contains_key(program_no))
+            .filter(|(_, asset_handle)| {
+                let Some(graph) = graphs.get(*asset_handle) else {
+                    return false;
+                };
+                modified_ids.contains(&asset_handle.id().untyped())
+                    || graph
+                        .midi_assets
+                        .iter()
+                        .any(|handle| modified_ids.contains(&handle.id().untyped()))
+                    || graph
+                        .sf2_assets
+                        .iter()
+                        .any(|handle| modified_ids.contains(&handle.id().untyped()))
+                    || graph
+                        .wave_assets
+                        .iter()
+                        .any(|handle| modified_ids.contains(&handle.id().untyped()))
+            })
+            .map(|(program_no, asset_handle)| (*program_no, asset_handle.clone()))
+            .collect();
+        programs_to_reload.sort_by_key(|(program_no, _)| *program_no);
+
         let mut loader =
             GraphAssetLoader::new(&asset_server, &midi_assets, &sf2_assets, &wave_assets);
-        let asset = graphs.get(&loading_asset_handle).unwrap();
-        let current_program_no = audio_context
-            .playing_program
-            .as_ref()
-            .map(|(program_no, _)| *program_no);
-        audio_context.store_new_program(loading_program_no, &asset.config, &mut loader)?;
-        match current_program_no {
-            Some(program_no) => {
-                if program_no != loading_program_no {
-                    audio_context.change_program(loading_program_no)?;
+        for (program_no, asset_handle) in programs_to_reload {
+            // Sub-assets newly referenced by a changed graph may still be loading; the graph's
+            // LoadedWithDependencies event will trigger another reload once they are ready
+            if !asset_server.is_loaded_with_dependencies(&asset_handle) {
+                continue;
+            }
+            let is_playing = audio_context.playing_program == Some(program_no);
+            let resume_seconds =
+                match is_playing && clock.is_running() && clock.program_no() == Some(program_no) {
+                    true => clock.mixer_seconds(),
+                    false => 0.0,
+                };
+            info!(
+                "Reloading program {} from {:.2}s",
+                program_no, resume_seconds
+            );
+            let asset = graphs.get(&asset_handle).unwrap();
+            let node = match asset.config.0.to_node(&mut loader) {
+                Ok(node) => node,
+                Err(error) => {
+                    load_failures.write(ProgramLoadFailed {
+                        program_no,
+                        asset_path: asset_server
+                            .get_path(&asset_handle)
+                            .map(|path| path.into_owned()),
+                        error,
+                    });
+                    continue;
                 }
+            };
+            // Replacing a rebuild that is still running drops its task, which cancels it
+            audio_context.rebuilding_programs.insert(
+                program_no,
+                ProgramRebuild {
+                    asset_handle,
+                    resume_seconds,
+                    task: spawn_fast_forward(node, resume_seconds),
+                },
+            );
+        }
+    }
+
+    pub fn has_rebuilding_programs(audio_context: Res<MidiGraphAudioContext>) -> bool {
+        !audio_context.rebuilding_programs.is_empty()
+    }
+
+    // Swap in rebuilt programs once they have been fast-forwarded. Their previous builds played on
+    // meanwhile, so a rebuilt program is first moved on by as far as the MusicClock has seen its
+    // previous build get since the rebuild started.
+    pub fn finish_rebuilt_programs(
+        mut audio_context: ResMut<MidiGraphAudioContext>,
+        clock: Res<MusicClock>,
+        mut load_failures: MessageWriter<ProgramLoadFailed>,
+        asset_server: Res<AssetServer>,
+    ) {
+        let rebuilt_programs: Vec<(usize, GraphNode)> = audio_context
+            .rebuilding_programs
+            .iter_mut()
+            .filter_map(|(program_no, rebuild)| {
+                Some((*program_no, check_ready(&mut rebuild.task)?))
+            })
+            .collect();
+        for (program_no, mut node) in rebuilt_programs {
+            let Some(mut rebuild) = audio_context.rebuilding_programs.remove(&program_no) else {
+                continue;
+            };
+            let is_playing = audio_context.playing_program == Some(program_no);
+            let stall_seconds =
+                match is_playing && clock.is_running() && clock.program_no() == Some(program_no) {
+                    true => (clock.mixer_seconds() - rebuild.resume_seconds).max(0.0),
+                    false => 0.0,
+                };
+            if stall_seconds > INLINE_CATCH_UP_SECONDS {
+                rebuild.resume_seconds += stall_seconds;
+                rebuild.task = spawn_fast_forward(node, stall_seconds);
+                audio_context
+                    .rebuilding_programs
+                    .insert(program_no, rebuild);
+                continue;
+            }
+            backend::fast_forward(&mut node, stall_seconds);
+            let mut result = audio_context.store_program_node(program_no, node);
+            if result.is_ok() && is_playing {
+                result = audio_context.change_program(program_no);
             }
-            None => {
-                audio_context.change_program(loading_program_no)?;
+            if let Err(error) = result {
+                load_failures.write(ProgramLoadFailed {
+                    program_no,
+                    asset_path: asset_server
+                        .get_path(&rebuild.asset_handle)
+                        .map(|path| path.into_owned()),
+                    error,
+                });
             }
         }
-        Ok(())
+    }
+
+    fn modified_asset_ids<A: Asset>(
+        events: &mut MessageReader<AssetEvent<A>>,
+    ) -> Vec<UntypedAssetId> {
+        events
+            .read()
+            .filter_map(|event| match event {
+                AssetEvent::Modified { id } => Some(id.untyped()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn finish_loading_program(
+        &mut self,
+        program_no: usize,
+        asset_handle: Handle<MidiGraph>,
+        config: &ChildConfig,
+        loader: &mut dyn AssetLoader,
+        switch_transition: Option<ProgramTransition>,
+    ) -> Result<(), Error> {
+        self.store_new_program(program_no, config, loader)?;
+        self.stored_programs.insert(program_no, asset_handle);
+        match switch_transition {
+            Some(transition) if self.playing_program != Some(program_no) => {
+                self.change_program_with_transition(program_no, transition)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Find the asset responsible for a failed load: the graph itself if it could not be parsed,
+    // otherwise the first sub-asset that failed.
+    pub(crate) fn find_failed_asset_path(
+        server: &AssetServer,
+        graphs: &Assets<MidiGraph>,
+        graph_handle: &Handle<MidiGraph>,
+    ) -> Option<AssetPath<'static>> {
+        let failed_id: UntypedAssetId = match graphs.get(graph_handle) {
+            None => graph_handle.id().untyped(),
+            Some(graph) => graph
+                .midi_assets
+                .iter()
+                .map(|handle| handle.id().untyped())
+                .chain(graph.sf2_assets.iter().map(|handle| handle.id().untyped()))
+                .chain(graph.wave_assets.iter().map(|handle| handle.id().untyped()))
+                .find(|id| matches!(server.load_state(*id), LoadState::Failed(_)))
+                .unwrap_or_else(|| graph_handle.id().untyped()),
+        };
+        server.get_path(failed_id).map(|path| path.into_owned())
     }
 
     pub fn start_new_program(
@@ -86,10 +372,72 @@ impl MidiGraphAudioContext {
         program_no: usize,
         asset_handle: Handle<MidiGraph>,
     ) {
-        self.loading_program = Some((program_no, asset_handle));
+        self.start_new_program_with_transition(
+            commands,
+            program_no,
+            asset_handle,
+            ProgramTransition::Immediate,
+        );
+    }
+
+    // Load a program and switch to it once ready, moving away from the current program with the
+    // given transition.
+    pub fn start_new_program_with_transition(
+        &mut self,
+        commands: &mut Commands,
+        program_no: usize,
+        asset_handle: Handle<MidiGraph>,
+        transition: ProgramTransition,
+    ) {
+        self.loading_programs.insert(program_no, asset_handle);
+        self.requested_program = Some((program_no, transition));
         commands.set_state(AudioContextState::Loading);
     }
 
+    // Load and store a program without switching to it, leaving the current program playing.
+    // A ProgramReady message is written once it can be switched to with change_program.
+    pub fn preload_program(&mut self, program_no: usize, asset_handle: Handle<MidiGraph>) {
+        self.loading_programs.insert(program_no, asset_handle);
+    }
+
+    pub fn loading_programs(&self) -> impl Iterator<Item = (usize, &Handle<MidiGraph>)> {
+        self.loading_programs
+            .iter()
+            .map(|(program_no, asset_handle)| (*program_no, asset_handle))
+    }
+
+    pub fn is_program_loading(&self, program_no: usize) -> bool {
+        self.loading_programs.contains_key(&program_no)
+    }
+
+    pub fn is_program_stored(&self, program_no: usize) -> bool {
+        self.stored_programs.contains_key(&program_no)
+    }
+
+    pub fn playing_program(&self) -> Option<usize> {
+        self.playing_program
+    }
+
+    // The graph asset a stored program was loaded from, if it was loaded as an asset
+    pub fn program_asset(&self, program_no: usize) -> Option<&Handle<MidiGraph>> {
+        self.stored_programs.get(&program_no)
+    }
+
+    // Start loading a program again after a ProgramLoadFailed message, reloading the asset that
+    // failed so the asset server doesn't return the cached failure.
+    pub fn retry_program(
+        &mut self,
+        commands: &mut Commands,
+        asset_server: &AssetServer,
+        failure: &ProgramLoadFailed,
+        asset_handle: Handle<MidiGraph>,
+    ) {
+        if let Some(asset_path) = &failure.asset_path {
+            asset_server.reload(asset_path.clone());
+        }
+        self.start_new_program(commands, failure.program_no, asset_handle);
+    }
+
     // Store a new program ready to be played later when requested.
     // Returns whether a program was already stored at the given program number.
     pub fn store_new_program(
@@ -98,48 +446,374 @@ impl MidiGraphAudioContext {
         config: &ChildConfig,
         loader: &mut dyn AssetLoader,
     ) -> Result<bool, Error> {
-        let mut mixer = match self.mixer.lock() {
-            Err(err) => {
-                return Err(Error::User(format!(
-                    "Mixer could not be locked to store program: {:?}",
-                    err
-                )));
-            }
-            Ok(mixer) => mixer,
-        };
         let node = config.0.to_node(loader)?;
-        let replaced_existing = mixer.0.store_program(program_no, node);
-        Ok(replaced_existing)
+        self.store_program_node(program_no, node)
+    }
+
+    fn store_program_node(&mut self, program_no: usize, node: GraphNode) -> Result<bool, Error> {
+        // The replaced program is dropped here, where freeing its samples doesn't hold up
+        // rendering
+        let replaced_program = self
+            .mixer
+            .run(move |mixer| mixer.store_program(program_no, node))?;
+        Ok(replaced_program.is_some())
     }
 
     pub fn change_program(&mut self, program_no: usize) -> Result<(), Error> {
-        let mut mixer = match self.mixer.lock() {
-            Err(err) => {
-                return Err(Error::User(format!(
-                    "Mixer could not be locked to store program: {:?}",
-                    err
-                )));
+        self.mixer
+            .run(move |mixer| mixer.change_program(program_no))??;
+        self.playing_program = Some(program_no);
+        Ok(())
+    }
+
+    // Switch to a stored program using the given transition. Any transition already in progress
+    // is completed straight away first.
+    pub fn change_program_with_transition(
+        &mut self,
+        program_no: usize,
+        transition: ProgramTransition,
+    ) -> Result<(), Error> {
+        self.complete_transition()?;
+        if transition == ProgramTransition::Immediate {
+            return self.change_program(program_no);
+        }
+        self.transition = Some(ActiveTransition::new(program_no, transition));
+        Ok(())
+    }
+
+    pub fn is_transitioning(&self) -> bool {
+        self.transition.is_some()
+    }
+
+    pub fn has_transition(audio_context: Res<MidiGraphAudioContext>) -> bool {
+        audio_context.transition.is_some()
+    }
+
+    pub fn advance_transition(
+        time: Res<Time>,
+        mut audio_context: ResMut<MidiGraphAudioContext>,
+        graphs: Res<Assets<MidiGraph>>,
+    ) -> Result<(), BevyError> {
+        let Some(mut transition) = audio_context.transition.take() else {
+            return Ok(());
+        };
+        transition.elapsed += time.delta();
+        if !audio_context.step_transition(&mut transition, &graphs)? {
+            audio_context.transition = Some(transition);
+        }
+        Ok(())
+    }
+
+    // Move a transition along, returning whether it has finished.
+    fn step_transition(
+        &mut self,
+        transition: &mut ActiveTransition,
+        graphs: &Assets<MidiGraph>,
+    ) -> Result<bool, Error> {
+        let program_no = transition.program_no;
+        match transition.transition {
+            ProgramTransition::Immediate => {
+                self.change_program(program_no)?;
+                Ok(true)
+            }
+            ProgramTransition::Crossfade(duration) => {
+                if let TransitionStage::Starting = transition.stage {
+                    self.switch_deck(program_no)?;
+                    transition.enter_stage(TransitionStage::Crossfading);
+                }
+                let TransitionStage::Crossfading = transition.stage else {
+                    return Ok(true);
+                };
+                // Equal-power curves keep the overall loudness steady through the crossfade
+                let progress = transition.progress(duration);
+                if progress >= 1.0 {
+                    self.finish_crossfade()?;
+                    return Ok(true);
+                }
+                let angle = progress * std::f32::consts::FRAC_PI_2;
+                self.set_crossfade_gains(angle.sin(), angle.cos())?;
+                Ok(false)
+            }
+            ProgramTransition::FadeOutIn { fade_out, fade_in } => {
+                if let TransitionStage::Starting = transition.stage {
+                    match self.playing_program {
+                        Some(_) => transition.enter_stage(TransitionStage::FadingOut),
+                        None => {
+                            self.change_program(program_no)?;
+                            transition.enter_stage(TransitionStage::FadingIn);
+                        }
+                    }
+                }
+                match transition.stage {
+                    TransitionStage::FadingOut => {
+                        let progress = transition.progress(fade_out);
+                        self.set_active_gain(1.0 - progress)?;
+                        if progress >= 1.0 {
+                            self.change_program(program_no)?;
+                            self.set_active_gain(0.0)?;
+                            transition.enter_stage(TransitionStage::FadingIn);
+                        }
+                        Ok(false)
+                    }
+                    TransitionStage::FadingIn => {
+                        let progress = transition.progress(fade_in);
+                        self.set_active_gain(progress)?;
+                        Ok(progress >= 1.0)
+                    }
+                    _ => Ok(true),
+                }
+            }
+            ProgramTransition::NextBeat | ProgramTransition::NextBar => {
+                let boundary_index =
+                    self.midi_position(graphs)
+                        .map(|position| match transition.transition {
+                            ProgramTransition::NextBar => position.bar_index(),
+                            _ => position.beat_index(),
+                        });
+                // Without a readable MIDI position there is nothing to wait for
+                let Some(boundary_index) = boundary_index else {
+                    self.change_program(program_no)?;
+                    return Ok(true);
+                };
+                match transition.stage {
+                    TransitionStage::WaitingForBoundary { start_index }
+                        if boundary_index == start_index =>
+                    {
+                        Ok(false)
+                    }
+                    TransitionStage::WaitingForBoundary { .. } => {
+                        self.change_program(program_no)?;
+                        Ok(true)
+                    }
+                    _ => {
+                        transition.enter_stage(TransitionStage::WaitingForBoundary {
+                            start_index: boundary_index,
+                        });
+                        Ok(false)
+                    }
+                }
             }
-            Ok(mixer) => mixer,
+        }
+    }
+
+    // Finish the current transition without waiting for it.
+    fn complete_transition(&mut self) -> Result<(), Error> {
+        let Some(transition) = self.transition.take() else {
+            return Ok(());
         };
-        mixer.0.change_program(program_no)?;
+        match transition.stage {
+            TransitionStage::Crossfading => {
+                self.finish_crossfade()?;
+            }
+            TransitionStage::FadingIn => {
+                self.set_active_gain(1.0)?;
+            }
+            TransitionStage::Starting
+            | TransitionStage::FadingOut
+            | TransitionStage::WaitingForBoundary { .. } => {
+                self.change_program(transition.program_no)?;
+                self.set_active_gain(1.0)?;
+            }
+        }
         Ok(())
     }
 
-    pub fn capture_node_state(&self, node_id: u64) -> Option<Result<Value, Error>> {
-        let mixer = match self.mixer.lock() {
+    fn switch_deck(&mut self, program_no: usize) -> Result<(), Error> {
+        self.mixer
+            .run(move |mixer| mixer.switch_deck(program_no))??;
+        self.playing_program = Some(program_no);
+        Ok(())
+    }
+
+    fn set_crossfade_gains(&self, active_gain: f32, previous_gain: f32) -> Result<(), Error> {
+        self.mixer.post(move |mixer| {
+            mixer.set_deck_gain(DeckSlot::Active, active_gain);
+            mixer.set_deck_gain(DeckSlot::Previous, previous_gain);
+        })
+    }
+
+    // The previous program is stopped once it has faded out, rather than left playing silently
+    fn finish_crossfade(&self) -> Result<(), Error> {
+        self.mixer.post(|mixer| {
+            mixer.set_deck_gain(DeckSlot::Active, 1.0);
+            mixer.stop_previous_deck();
+        })
+    }
+
+    fn set_active_gain(&self, gain: f32) -> Result<(), Error> {
+        self.mixer
+            .post(move |mixer| mixer.set_deck_gain(DeckSlot::Active, gain))
+    }
+
+    // Position of the first Midi node in the playing program, if its state can be captured
+    fn midi_position(&self, graphs: &Assets<MidiGraph>) -> Option<MidiPosition> {
+        let asset_handle = self.stored_programs.get(&self.playing_program?)?;
+        let midi_node_id = graphs.get(asset_handle)?.node_ids_of_type("Midi").min()?;
+        self.capture_midi_position(midi_node_id)
+    }
+
+    // Node id of a named node in the playing program's graph
+    // The graph asset the playing program was loaded from
+    pub fn playing_graph(&self) -> Option<AssetId<MidiGraph>> {
+        self.program_asset(self.playing_program?).map(Handle::id)
+    }
+
+    pub fn playing_node_id(&self, graphs: &Assets<MidiGraph>, name: &str) -> Option<u64> {
+        let asset_handle = self.stored_programs.get(&self.playing_program?)?;
+        graphs.get(asset_handle)?.node_id(name)
+    }
+
+    // Musical position of a Midi node in the playing program, if its state can be captured
+    pub fn capture_midi_position(&self, node_id: u64) -> Option<MidiPosition> {
+        let snapshot = self.capture_node_state(node_id)?.ok()?;
+        match MidiPosition::from_snapshot(snapshot) {
+            // A node that hasn't read its track yet has no position
+            Ok(position) => Some(position).filter(|position| position.ticks_per_beat > 0),
             Err(err) => {
-                return Some(Err(Error::User(format!(
-                    "Mixer could not be locked to capture state: {:?}",
+                warn_once!(
+                    "Cannot read the position of Midi node {}: {:?}",
+                    node_id,
                     err
-                ))));
+                );
+                None
             }
-            Ok(mixer) => mixer,
-        };
-        mixer.0.get_active_node_state_snapshot(node_id)
+        }
+    }
+
+    pub fn capture_node_state(&self, node_id: u64) -> Option<Result<Value, Error>> {
+        self.mixer
+            .run(move |mixer| mixer.get_active_node_state_snapshot(node_id))
+            .unwrap_or_else(|err| Some(Err(err)))
+    }
+
+    // How far the device's output trails the mixer, as measured by its output stream. Not
+    // available with the other backends, or before the stream has started.
+    pub fn output_latency(&self) -> Option<Duration> {
+        self.mixer.output_latency()
+    }
+
+    pub(crate) fn mixer(&self) -> &MixerThread {
+        &self.mixer
     }
 
     pub fn get_event_sender(&mut self) -> Arc<MessageSender> {
         self.event_sender.clone()
     }
+
+    pub fn send_commands(
+        audio_context: Res<MidiGraphAudioContext>,
+        graphs: Res<Assets<MidiGraph>>,
+        mut graph_commands: ResMut<Messages<MidiGraphCommand>>,
+        mut command_failures: MessageWriter<MidiGraphCommandFailed>,
+    ) {
+        for mut command in graph_commands.drain() {
+            if let Some(graph) = command.graph
+                && audio_context.playing_graph() != Some(graph)
+            {
+                command_failures.write(MidiGraphCommandFailed {
+                    error: Error::User(format!(
+                        "Command for graph {:?} sent while it isn't the playing program",
+                        graph
+                    )),
+                });
+                continue;
+            }
+            if let Some(node_name) = command.node_name.take() {
+                let Some(node_id) = audio_context.playing_node_id(&graphs, &node_name) else {
+                    command_failures.write(MidiGraphCommandFailed {
+                        error: Error::User(format!(
+                            "No node named \"{}\" in the playing program",
+                            node_name
+                        )),
+                    });
+                    continue;
+                };
+                command.target = EventTarget::SpecificNode(node_id);
+            }
+            let send = audio_context.event_sender.send(command.into_message());
+            if let Err(err) = send {
+                command_failures.write(MidiGraphCommandFailed {
+                    error: Error::User(format!("Could not send event to mixer: {:?}", err)),
+                });
+            }
+        }
+    }
+
+    // Render frames from the offline mixer on demand. Fails if the context was started with the
+    // device backend.
+    pub fn render_frames(&mut self, frame_count: usize) -> Result<Vec<f32>, Error> {
+        if !self.mixer.is_offline() {
+            return Err(Error::User(
+                "Rendering on demand requires an offline backend".to_owned(),
+            ));
+        }
+        self.mixer
+            .run(move |mixer| mixer.render_frames(frame_count))
+    }
+
+    // Frames follow real time, like a device would pull them. Pausing or speeding up with virtual
+    // time is left to the mixer's playback rate, so that it isn't applied twice.
+    pub fn render_offline_audio(
+        time: Res<Time<Real>>,
+        audio_context: Res<MidiGraphAudioContext>,
+        mut output: ResMut<OfflineAudioOutput>,
+        mut pending_frames: Local<f64>,
+    ) -> Result<(), BevyError> {
+        let frame_count = match output.frames_per_update {
+            Some(frame_count) => frame_count,
+            None => frames_elapsed(&time, &mut pending_frames),
+        };
+        if frame_count == 0 {
+            return Ok(());
+        }
+        let samples = audio_context
+            .mixer
+            .run(move |mixer| mixer.render_frames(frame_count))?;
+        output.append(&samples);
+        Ok(())
+    }
+
+    // Pause the mixer, or change its playback rate, to follow virtual time
+    pub fn sync_virtual_time(
+        time: Res<Time<Virtual>>,
+        audio_context: Res<MidiGraphAudioContext>,
+        sync: Res<VirtualTimeSync>,
+        mut synced_rate: Local<Option<f32>>,
+    ) -> Result<(), BevyError> {
+        let rate = sync.playback_rate(&time);
+        if synced_rate.unwrap_or(1.0) == rate {
+            return Ok(());
+        }
+        audio_context
+            .mixer
+            .post(move |mixer| mixer.set_playback_rate(rate))?;
+        *synced_rate = Some(rate);
+        Ok(())
+    }
+
+    // Render the mixer and discard the audio as real time passes, so that programs advance and
+    // their positions can be followed without a device
+    pub fn render_null_audio(
+        time: Res<Time<Real>>,
+        audio_context: Res<MidiGraphAudioContext>,
+        mut pending_frames: Local<f64>,
+    ) -> Result<(), BevyError> {
+        let frame_count = frames_elapsed(&time, &mut pending_frames);
+        if frame_count == 0 {
+            return Ok(());
+        }
+        audio_context
+            .mixer
+            .run(move |mixer| mixer.render_frames(frame_count))?;
+        Ok(())
+    }
+}
+
+// Whole frames at the mixer's sample rate that fit in the time since the last update, carrying the
+// remainder over to the next one
+fn frames_elapsed(time: &Time<Real>, pending_frames: &mut f64) -> usize {
+    *pending_frames += time.delta_secs_f64() * OfflineAudioOutput::SAMPLE_RATE as f64;
+    let whole_frames = pending_frames.floor();
+    *pending_frames -= whole_frames;
+    whole_frames as usize
 }
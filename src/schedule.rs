@@ -0,0 +1,119 @@
+use crate::{
+    MidiFileSource, MidiGraph, MidiGraphAudioContext, MusicClock,
+    message::{MidiGraphCommand, ScheduledMidiGraphCommand},
+    playback::PlaybackTracker,
+    timeline::MidiTimeline,
+};
+use bevy::prelude::*;
+use std::sync::Arc;
+
+/// A musical moment in the playing program's MIDI track, as followed by [`MusicClock`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MusicalTime {
+    NextBeat,
+    NextBar,
+    /// A beat of a bar, both counted from zero. Commands scheduled for a moment that has
+    /// already passed are delivered straight away.
+    At {
+        bar: u64,
+        beat: u32,
+    },
+}
+
+struct PendingCommand {
+    command: MidiGraphCommand,
+    at: MusicalTime,
+    target_tick: Option<u64>,
+    scheduled_tick: u64,
+}
+
+// Commands waiting for their musical moment. They are delivered on the first frame the mixer
+// reaches it, so they can land up to a frame late.
+#[derive(Resource, Default)]
+pub struct CommandSchedule {
+    pending: Vec<PendingCommand>,
+}
+
+impl CommandSchedule {
+    pub fn deliver_scheduled_commands(
+        mut schedule: ResMut<CommandSchedule>,
+        mut tracker: ResMut<PlaybackTracker>,
+        mut scheduled_commands: ResMut<Messages<ScheduledMidiGraphCommand>>,
+        mut graph_commands: MessageWriter<MidiGraphCommand>,
+        clock: Res<MusicClock>,
+        audio_context: Res<MidiGraphAudioContext>,
+        asset_server: Res<AssetServer>,
+        graphs: Res<Assets<MidiGraph>>,
+        midi_assets: Res<Assets<MidiFileSource>>,
+    ) {
+        let mixer_tick = clock.mixer_tick();
+        for scheduled in scheduled_commands.drain() {
+            schedule.pending.push(PendingCommand {
+                command: scheduled.command,
+                at: scheduled.at,
+                target_tick: None,
+                scheduled_tick: mixer_tick,
+            });
+        }
+        if schedule.pending.is_empty() {
+            return;
+        }
+
+        // Without a position to follow there is nothing to wait for, as with quantized
+        // transitions
+        let timeline = clock
+            .is_running()
+            .then(|| {
+                Self::clock_timeline(
+                    &clock,
+                    &mut tracker,
+                    &audio_context,
+                    &asset_server,
+                    &graphs,
+                    &midi_assets,
+                )
+            })
+            .flatten();
+        let Some(timeline) = timeline else {
+            for pending in schedule.pending.drain(..) {
+                graph_commands.write(pending.command);
+            }
+            return;
+        };
+
+        let mut waiting = vec![];
+        for mut pending in schedule.pending.drain(..) {
+            // After a loop or seek, relative times are measured from the new position
+            if mixer_tick < pending.scheduled_tick {
+                pending.scheduled_tick = mixer_tick;
+                if !matches!(pending.at, MusicalTime::At { .. }) {
+                    pending.target_tick = None;
+                }
+            }
+            let target_tick = *pending.target_tick.get_or_insert_with(|| match pending.at {
+                MusicalTime::NextBeat => timeline.next_beat_tick(pending.scheduled_tick),
+                MusicalTime::NextBar => timeline.next_bar_tick(pending.scheduled_tick),
+                MusicalTime::At { bar, beat } => timeline.tick_at_bar_beat(bar, beat),
+            });
+            if mixer_tick >= target_tick {
+                graph_commands.write(pending.command);
+            } else {
+                waiting.push(pending);
+            }
+        }
+        schedule.pending = waiting;
+    }
+
+    fn clock_timeline(
+        clock: &MusicClock,
+        tracker: &mut PlaybackTracker,
+        audio_context: &MidiGraphAudioContext,
+        asset_server: &AssetServer,
+        graphs: &Assets<MidiGraph>,
+        midi_assets: &Assets<MidiFileSource>,
+    ) -> Option<Arc<MidiTimeline>> {
+        let graph = graphs.get(audio_context.program_asset(clock.program_no()?)?)?;
+        let source = graph.midi_sources.get(&clock.node_id()?)?;
+        tracker.timeline(asset_server, midi_assets, source)
+    }
+}
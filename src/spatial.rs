@@ -0,0 +1,112 @@
+use crate::{MidiGraphPlayer, MidiGraphPlayers};
+use bevy::prelude::*;
+use midi_graph::MessageSender;
+use std::{collections::HashMap, sync::Arc};
+
+// Changes smaller than this are not sent, to avoid flooding the mixer while things barely move
+const CHANGE_THRESHOLD: f32 = 1.0e-3;
+
+/// Positions the graph of a [`MidiGraphPlayer`] on the same entity in the world, relative to the
+/// [`MidiGraphListener`]. Its volume falls off with distance and it is panned towards the side
+/// of the listener it is on.
+#[derive(Component, Clone, Debug)]
+pub struct MidiGraphEmitter {
+    /// Volume when within the reference distance of the listener.
+    pub volume: f32,
+    /// Distance within which the emitter plays at full volume.
+    pub reference_distance: f32,
+    /// Distance beyond which the emitter is silent.
+    pub max_distance: f32,
+    /// How quickly volume falls off beyond the reference distance.
+    pub rolloff: f32,
+    /// How far the emitter can be panned to one side, from 0 (never) to 1 (fully).
+    pub pan_strength: f32,
+}
+
+impl Default for MidiGraphEmitter {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            reference_distance: 1.0,
+            max_distance: 50.0,
+            rolloff: 1.0,
+            pan_strength: 1.0,
+        }
+    }
+}
+
+impl MidiGraphEmitter {
+    // Set each emitter's gain and pan on its player's graph, as heard by the listener
+    pub fn update_emitters(
+        players: Res<MidiGraphPlayers>,
+        listener_query: Query<&GlobalTransform, With<MidiGraphListener>>,
+        emitter_query: Query<(Entity, &MidiGraphEmitter, &GlobalTransform), With<MidiGraphPlayer>>,
+        mut sent_values: Local<HashMap<Entity, SentValues>>,
+    ) -> Result<(), BevyError> {
+        let Some(listener_transform) = listener_query.iter().next() else {
+            return Ok(());
+        };
+        let world_to_listener = listener_transform.affine().inverse();
+        sent_values.retain(|entity, _| players.is_playing(*entity));
+        for (entity, emitter, emitter_transform) in emitter_query.iter() {
+            let Some(event_sender) = players.get_event_sender(entity) else {
+                continue;
+            };
+            let local_position =
+                world_to_listener.transform_point3(emitter_transform.translation());
+            let gain = emitter.gain_at(local_position.length());
+            let pan = emitter.pan_at(local_position);
+            // A player that restarted has a new sender, and needs its values sent again
+            if let Some(sent) = sent_values.get(&entity)
+                && Arc::ptr_eq(&sent.event_sender, &event_sender)
+                && (gain - sent.gain).abs() < CHANGE_THRESHOLD
+                && (pan - sent.pan).abs() < CHANGE_THRESHOLD
+            {
+                continue;
+            }
+            sent_values.insert(
+                entity,
+                SentValues {
+                    event_sender: event_sender.clone(),
+                    gain,
+                    pan,
+                },
+            );
+            players.set_gain_and_pan(entity, gain, pan)?;
+        }
+        Ok(())
+    }
+
+    // Inverse distance falloff, cut off at the maximum distance
+    fn gain_at(&self, distance: f32) -> f32 {
+        if distance >= self.max_distance {
+            return 0.0;
+        }
+        let reference_distance = self.reference_distance.max(f32::EPSILON);
+        if distance <= reference_distance {
+            return self.volume;
+        }
+        self.volume * reference_distance
+            / (reference_distance + self.rolloff * (distance - reference_distance))
+    }
+
+    // Pan from -1 (left) to 1 (right), given the emitter's position in the listener's space
+    fn pan_at(&self, local_position: Vec3) -> f32 {
+        let distance = local_position.length();
+        if distance <= f32::EPSILON {
+            return 0.0;
+        }
+        (local_position.x / distance).clamp(-1.0, 1.0) * self.pan_strength.clamp(0.0, 1.0)
+    }
+}
+
+pub struct SentValues {
+    event_sender: Arc<MessageSender>,
+    gain: f32,
+    pan: f32,
+}
+
+/// Marks the entity that [`MidiGraphEmitter`]s are heard from, usually the camera. Only one
+/// listener should exist at a time.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct MidiGraphListener;
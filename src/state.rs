@@ -5,4 +5,5 @@ pub enum AudioContextState {
     None,
     Loading,
     Running,
+    Failed,
 }
@@ -0,0 +1,86 @@
+use crate::{MidiGraph, MidiGraphAudioContext, ProgramTransition};
+use bevy::{asset::AssetPath, prelude::*};
+
+/// Music that plays while the app is in a given state.
+pub trait MidiGraphAppExt {
+    /// Preload a graph as a program at startup, and switch to it whenever `state` is entered.
+    /// Add this after [`MidiGraphPlugin`](crate::MidiGraphPlugin).
+    fn add_music_for_state<S: States>(
+        &mut self,
+        state: S,
+        program_no: usize,
+        path: impl Into<AssetPath<'static>>,
+    ) -> &mut Self;
+
+    /// As [`add_music_for_state`](MidiGraphAppExt::add_music_for_state), switching to the
+    /// program with the given transition.
+    fn add_music_for_state_with_transition<S: States>(
+        &mut self,
+        state: S,
+        program_no: usize,
+        path: impl Into<AssetPath<'static>>,
+        transition: ProgramTransition,
+    ) -> &mut Self;
+}
+
+impl MidiGraphAppExt for App {
+    fn add_music_for_state<S: States>(
+        &mut self,
+        state: S,
+        program_no: usize,
+        path: impl Into<AssetPath<'static>>,
+    ) -> &mut Self {
+        self.add_music_for_state_with_transition(
+            state,
+            program_no,
+            path,
+            ProgramTransition::Immediate,
+        )
+    }
+
+    fn add_music_for_state_with_transition<S: States>(
+        &mut self,
+        state: S,
+        program_no: usize,
+        path: impl Into<AssetPath<'static>>,
+        transition: ProgramTransition,
+    ) -> &mut Self {
+        let path = path.into();
+        let preload_path = path.clone();
+        self.add_systems(
+            Startup,
+            move |mut audio_context: ResMut<MidiGraphAudioContext>,
+                  asset_server: Res<AssetServer>| {
+                let asset_handle: Handle<MidiGraph> = asset_server.load(preload_path.clone());
+                audio_context.preload_program(program_no, asset_handle);
+            },
+        )
+        .add_systems(
+            OnEnter(state),
+            move |mut commands: Commands,
+                  mut audio_context: ResMut<MidiGraphAudioContext>,
+                  asset_server: Res<AssetServer>|
+                  -> Result<(), BevyError> {
+                if audio_context.playing_program() == Some(program_no) {
+                    return Ok(());
+                }
+                // A preloaded program can be switched to straight away; otherwise it is played
+                // once it has finished loading
+                if audio_context.is_program_stored(program_no)
+                    && !audio_context.is_program_loading(program_no)
+                {
+                    audio_context.change_program_with_transition(program_no, transition)?;
+                    return Ok(());
+                }
+                let asset_handle: Handle<MidiGraph> = asset_server.load(path.clone());
+                audio_context.start_new_program_with_transition(
+                    &mut commands,
+                    program_no,
+                    asset_handle,
+                    transition,
+                );
+                Ok(())
+            },
+        )
+    }
+}
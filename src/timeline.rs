@@ -0,0 +1,585 @@
+use midi_graph::Error;
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+pub struct TimelineNote {
+    pub tick: u64,
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: u8,
+}
+
+pub struct TimelineCue {
+    pub tick: u64,
+    pub label: String,
+}
+
+pub struct TimelineTempo {
+    pub tick: u64,
+    pub microseconds_per_beat: u32,
+}
+
+pub struct TimelineTimeSignature {
+    pub tick: u64,
+    pub numerator: u8,
+    pub denominator: u8,
+}
+
+// Musical position within a track, counted from zero
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BarBeat {
+    pub bar: u64,
+    pub beat: u32,
+    pub beat_fraction: f32,
+    pub time_signature: (u8, u8),
+}
+
+struct SignatureSegment {
+    start_tick: u64,
+    start_bar: u64,
+    time_signature: (u8, u8),
+}
+
+const DEFAULT_MICROSECONDS_PER_BEAT: u32 = 500000;
+// How much further than the elapsed time playback may move before it counts as a jump. The mixer
+// renders ahead in blocks, so positions don't advance exactly with the frame time.
+const JUMP_SLACK_SECONDS: f64 = 0.25;
+
+// The events of one MIDI track that playback notifications are derived from, with ticks
+// measured from the start of the track.
+pub struct MidiTimeline {
+    pub ticks_per_beat: u16,
+    pub length_ticks: u64,
+    pub notes: Vec<TimelineNote>,
+    pub cues: Vec<TimelineCue>,
+    pub tempos: Vec<TimelineTempo>,
+    pub time_signatures: Vec<TimelineTimeSignature>,
+}
+
+impl MidiTimeline {
+    pub fn parse(bytes: &[u8], track_index: usize) -> Result<Self, Error> {
+        let smf = Smf::parse(bytes)
+            .map_err(|err| Error::User(format!("Cannot parse MIDI file: {:?}", err)))?;
+        let ticks_per_beat = match smf.header.timing {
+            Timing::Metrical(ticks_per_beat) => ticks_per_beat.as_int(),
+            Timing::Timecode(_, _) => {
+                return Err(Error::User(
+                    "Timecode-based MIDI files are not supported".to_owned(),
+                ));
+            }
+        };
+        let track = smf
+            .tracks
+            .get(track_index)
+            .ok_or_else(|| Error::User(format!("MIDI track {} not found", track_index)))?;
+        let mut timeline = MidiTimeline {
+            ticks_per_beat,
+            length_ticks: 0,
+            notes: vec![],
+            cues: vec![],
+            tempos: vec![],
+            time_signatures: vec![],
+        };
+        let mut tick = 0;
+        for event in track.iter() {
+            tick += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::NoteOn { key, vel },
+                } if vel.as_int() > 0 => timeline.notes.push(TimelineNote {
+                    tick,
+                    channel: channel.as_int(),
+                    note: key.as_int(),
+                    velocity: vel.as_int(),
+                }),
+                TrackEventKind::Meta(MetaMessage::Marker(label))
+                | TrackEventKind::Meta(MetaMessage::CuePoint(label)) => {
+                    timeline.cues.push(TimelineCue {
+                        tick,
+                        label: String::from_utf8_lossy(label).into_owned(),
+                    })
+                }
+                TrackEventKind::Meta(MetaMessage::Tempo(microseconds_per_beat)) => {
+                    timeline.tempos.push(TimelineTempo {
+                        tick,
+                        microseconds_per_beat: microseconds_per_beat.as_int(),
+                    })
+                }
+                TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, _, _)) => {
+                    timeline.time_signatures.push(TimelineTimeSignature {
+                        tick,
+                        numerator: numerator.max(1),
+                        denominator: 2u8.saturating_pow(denominator as u32).max(1),
+                    })
+                }
+                _ => {}
+            }
+        }
+        timeline.length_ticks = tick;
+        Self::add_conductor_events(&smf, track_index, &mut timeline);
+        Ok(timeline)
+    }
+
+    pub fn track_count(bytes: &[u8]) -> Result<usize, Error> {
+        let smf = Smf::parse(bytes)
+            .map_err(|err| Error::User(format!("Cannot parse MIDI file: {:?}", err)))?;
+        Ok(smf.tracks.len())
+    }
+
+    // Tempo and time signature events usually live in the first track of a multi-track file,
+    // rather than the track being played.
+    fn add_conductor_events(smf: &Smf, track_index: usize, timeline: &mut MidiTimeline) {
+        if track_index == 0 || !timeline.tempos.is_empty() {
+            return;
+        }
+        let Some(conductor_track) = smf.tracks.first() else {
+            return;
+        };
+        let mut tick = 0;
+        for event in conductor_track.iter() {
+            tick += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(microseconds_per_beat)) => {
+                    timeline.tempos.push(TimelineTempo {
+                        tick,
+                        microseconds_per_beat: microseconds_per_beat.as_int(),
+                    })
+                }
+                TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, _, _))
+                    if timeline.time_signatures.is_empty() || tick > 0 =>
+                {
+                    timeline.time_signatures.push(TimelineTimeSignature {
+                        tick,
+                        numerator: numerator.max(1),
+                        denominator: 2u8.saturating_pow(denominator as u32).max(1),
+                    })
+                }
+                _ => {}
+            }
+        }
+        timeline
+            .time_signatures
+            .sort_by_key(|signature| signature.tick);
+    }
+
+    pub fn microseconds_per_beat_at(&self, tick: u64) -> u32 {
+        self.tempos
+            .iter()
+            .take_while(|tempo| tempo.tick <= tick)
+            .last()
+            .map(|tempo| tempo.microseconds_per_beat)
+            .unwrap_or(DEFAULT_MICROSECONDS_PER_BEAT)
+    }
+
+    // Time from the start of the track to the given tick, following tempo changes
+    pub fn seconds_at(&self, tick: u64) -> f64 {
+        let mut seconds = 0.0;
+        let mut segment_start = 0;
+        let mut microseconds_per_beat = DEFAULT_MICROSECONDS_PER_BEAT;
+        for tempo in self.tempos.iter().take_while(|tempo| tempo.tick <= tick) {
+            seconds += self.ticks_to_seconds(tempo.tick - segment_start, microseconds_per_beat);
+            segment_start = tempo.tick;
+            microseconds_per_beat = tempo.microseconds_per_beat;
+        }
+        seconds + self.ticks_to_seconds(tick - segment_start, microseconds_per_beat)
+    }
+
+    // The tick reached after the given time from the start of the track
+    pub fn tick_at_seconds(&self, seconds: f64) -> u64 {
+        let mut segment_seconds = 0.0;
+        let mut segment_start = 0;
+        let mut microseconds_per_beat = DEFAULT_MICROSECONDS_PER_BEAT;
+        for tempo in self.tempos.iter() {
+            let tempo_seconds = segment_seconds
+                + self.ticks_to_seconds(tempo.tick - segment_start, microseconds_per_beat);
+            if tempo_seconds > seconds {
+                break;
+            }
+            segment_seconds = tempo_seconds;
+            segment_start = tempo.tick;
+            microseconds_per_beat = tempo.microseconds_per_beat;
+        }
+        let beats = (seconds - segment_seconds) * 1.0e6 / microseconds_per_beat as f64;
+        segment_start + (beats * self.ticks_per_beat as f64).max(0.0) as u64
+    }
+
+    fn ticks_to_seconds(&self, ticks: u64, microseconds_per_beat: u32) -> f64 {
+        ticks as f64 / self.ticks_per_beat as f64 * microseconds_per_beat as f64 * 1.0e-6
+    }
+
+    // Bar and beat at the given tick, in the units of the time signature in effect. Bars are
+    // counted across time signature changes, with a partial bar before a change counting as one.
+    pub fn bar_beat_at(&self, tick: u64) -> BarBeat {
+        let segment = self.segment_at(tick);
+        let ticks_per_beat = self.ticks_per_signature_beat(segment.time_signature);
+        let ticks_per_bar = self.ticks_per_bar(segment.time_signature);
+        let offset = tick - segment.start_tick;
+        let ticks_into_bar = offset % ticks_per_bar;
+        BarBeat {
+            bar: segment.start_bar + offset / ticks_per_bar,
+            beat: (ticks_into_bar / ticks_per_beat) as u32,
+            beat_fraction: (ticks_into_bar % ticks_per_beat) as f32 / ticks_per_beat as f32,
+            time_signature: segment.time_signature,
+        }
+    }
+
+    // The first beat starting after the given tick
+    pub fn next_beat_tick(&self, tick: u64) -> u64 {
+        let segment = self.segment_at(tick);
+        let ticks_per_beat = self.ticks_per_signature_beat(segment.time_signature);
+        let next_beat = segment.start_tick
+            + ((tick - segment.start_tick) / ticks_per_beat + 1) * ticks_per_beat;
+        self.clamp_to_next_segment(tick, next_beat)
+    }
+
+    // The first bar starting after the given tick
+    pub fn next_bar_tick(&self, tick: u64) -> u64 {
+        let segment = self.segment_at(tick);
+        let ticks_per_bar = self.ticks_per_bar(segment.time_signature);
+        let next_bar =
+            segment.start_tick + ((tick - segment.start_tick) / ticks_per_bar + 1) * ticks_per_bar;
+        self.clamp_to_next_segment(tick, next_bar)
+    }
+
+    // The tick at which a beat of a bar starts, both counted from zero
+    pub fn tick_at_bar_beat(&self, bar: u64, beat: u32) -> u64 {
+        let mut segment = self.segment_at(0);
+        for signature in self.time_signatures.iter() {
+            let next_segment = self.segment_at(signature.tick);
+            if next_segment.start_bar > bar {
+                break;
+            }
+            segment = next_segment;
+        }
+        let ticks_per_beat = self.ticks_per_signature_beat(segment.time_signature);
+        let ticks_per_bar = self.ticks_per_bar(segment.time_signature);
+        segment.start_tick
+            + (bar - segment.start_bar) * ticks_per_bar
+            + beat as u64 * ticks_per_beat
+    }
+
+    // A new time signature always starts a new bar, so boundaries found using the previous
+    // signature don't reach past it
+    fn clamp_to_next_segment(&self, tick: u64, boundary: u64) -> u64 {
+        self.time_signatures
+            .iter()
+            .map(|signature| signature.tick)
+            .find(|signature_tick| *signature_tick > tick)
+            .map_or(boundary, |signature_tick| boundary.min(signature_tick))
+    }
+
+    // The span of the track with the same time signature that contains the given tick
+    fn segment_at(&self, tick: u64) -> SignatureSegment {
+        let mut segment = SignatureSegment {
+            start_tick: 0,
+            start_bar: 0,
+            time_signature: (4, 4),
+        };
+        for signature in self
+            .time_signatures
+            .iter()
+            .take_while(|signature| signature.tick <= tick)
+        {
+            let ticks_per_bar = self.ticks_per_bar(segment.time_signature);
+            segment.start_bar += (signature.tick - segment.start_tick).div_ceil(ticks_per_bar);
+            segment.start_tick = signature.tick;
+            segment.time_signature = (signature.numerator, signature.denominator);
+        }
+        segment
+    }
+
+    // Ticks in one beat of a time signature, where the denominator gives the note value of a beat
+    fn ticks_per_signature_beat(&self, time_signature: (u8, u8)) -> u64 {
+        (self.ticks_per_beat as u64 * 4 / time_signature.1 as u64).max(1)
+    }
+
+    fn ticks_per_bar(&self, time_signature: (u8, u8)) -> u64 {
+        self.ticks_per_signature_beat(time_signature) * time_signature.0 as u64
+    }
+
+    // Whether playback moving from one tick to another, while the given time of audio played,
+    // must have jumped rather than played through. Moving back is always a jump.
+    pub fn is_jump(&self, from_tick: u64, to_tick: u64, elapsed_seconds: f64) -> bool {
+        if to_tick < from_tick {
+            return true;
+        }
+        let advanced_seconds = self.seconds_at(to_tick) - self.seconds_at(from_tick);
+        advanced_seconds > elapsed_seconds + JUMP_SLACK_SECONDS
+    }
+
+    // Notes starting in the tick range (after, until]
+    pub fn notes_between(&self, after: u64, until: u64) -> impl Iterator<Item = &TimelineNote> {
+        self.notes
+            .iter()
+            .filter(move |note| note.tick > after && note.tick <= until)
+    }
+
+    // Cues placed in the tick range (after, until]
+    pub fn cues_between(&self, after: u64, until: u64) -> impl Iterator<Item = &TimelineCue> {
+        self.cues
+            .iter()
+            .filter(move |cue| cue.tick > after && cue.tick <= until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::{
+        Format, Header, MidiMessage, TrackEvent,
+        num::{u4, u7, u15, u24, u28},
+    };
+
+    const TICKS_PER_BEAT: u16 = 480;
+
+    // Write a MIDI file with a track for each list of events, given at absolute ticks
+    fn midi_file(tracks: Vec<Vec<(u64, TrackEventKind<'static>)>>) -> Vec<u8> {
+        let timing = Timing::Metrical(u15::new(TICKS_PER_BEAT));
+        let mut smf = Smf::new(Header::new(Format::Parallel, timing));
+        for events in tracks {
+            let mut previous_tick = 0;
+            let mut track: Vec<TrackEvent> = events
+                .into_iter()
+                .map(|(tick, kind)| {
+                    let delta = u28::new((tick - previous_tick) as u32);
+                    previous_tick = tick;
+                    TrackEvent { delta, kind }
+                })
+                .collect();
+            track.push(TrackEvent {
+                delta: u28::new(0),
+                kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+            });
+            smf.tracks.push(track);
+        }
+        let mut bytes = vec![];
+        smf.write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn note_on(channel: u8, key: u8, vel: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Midi {
+            channel: u4::new(channel),
+            message: MidiMessage::NoteOn {
+                key: u7::new(key),
+                vel: u7::new(vel),
+            },
+        }
+    }
+
+    fn tempo(microseconds_per_beat: u32) -> TrackEventKind<'static> {
+        TrackEventKind::Meta(MetaMessage::Tempo(u24::new(microseconds_per_beat)))
+    }
+
+    // The denominator is given as a power of two, as in MIDI files
+    fn time_signature(numerator: u8, denominator_power: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Meta(MetaMessage::TimeSignature(
+            numerator,
+            denominator_power,
+            24,
+            8,
+        ))
+    }
+
+    #[test]
+    fn parse_collects_notes_cues_and_length() {
+        let bytes = midi_file(vec![vec![
+            (0, TrackEventKind::Meta(MetaMessage::Marker(b"intro"))),
+            (0, note_on(0, 60, 100)),
+            (240, note_on(0, 60, 0)),
+            (480, note_on(9, 36, 90)),
+            (960, TrackEventKind::Meta(MetaMessage::CuePoint(b"loop"))),
+            (1200, note_on(9, 36, 0)),
+        ]]);
+        let timeline = MidiTimeline::parse(&bytes, 0).unwrap();
+
+        assert_eq!(timeline.ticks_per_beat, TICKS_PER_BEAT);
+        assert_eq!(timeline.length_ticks, 1200);
+        // Note-ons with zero velocity are note-offs
+        let notes: Vec<_> = timeline
+            .notes
+            .iter()
+            .map(|note| (note.tick, note.channel, note.note, note.velocity))
+            .collect();
+        assert_eq!(notes, vec![(0, 0, 60, 100), (480, 9, 36, 90)]);
+        let cues: Vec<_> = timeline
+            .cues
+            .iter()
+            .map(|cue| (cue.tick, cue.label.as_str()))
+            .collect();
+        assert_eq!(cues, vec![(0, "intro"), (960, "loop")]);
+    }
+
+    #[test]
+    fn notes_and_cues_between_exclude_the_start_tick() {
+        let bytes = midi_file(vec![vec![
+            (0, note_on(0, 60, 100)),
+            (480, note_on(0, 62, 100)),
+            (480, TrackEventKind::Meta(MetaMessage::Marker(b"verse"))),
+            (960, note_on(0, 64, 100)),
+        ]]);
+        let timeline = MidiTimeline::parse(&bytes, 0).unwrap();
+
+        let notes: Vec<u8> = timeline
+            .notes_between(0, 960)
+            .map(|note| note.note)
+            .collect();
+        assert_eq!(notes, vec![62, 64]);
+        assert_eq!(timeline.notes_between(480, 959).count(), 0);
+        assert_eq!(timeline.cues_between(0, 480).count(), 1);
+        assert_eq!(timeline.cues_between(480, 960).count(), 0);
+    }
+
+    #[test]
+    fn conductor_track_tempo_applies_to_other_tracks() {
+        let bytes = midi_file(vec![
+            vec![(0, tempo(400000)), (0, time_signature(3, 2))],
+            vec![(0, note_on(0, 60, 100)), (480, note_on(0, 62, 100))],
+        ]);
+        let timeline = MidiTimeline::parse(&bytes, 1).unwrap();
+
+        assert_eq!(timeline.notes.len(), 2);
+        assert_eq!(timeline.microseconds_per_beat_at(0), 400000);
+        assert_eq!(timeline.time_signatures.len(), 1);
+        assert_eq!(timeline.time_signatures[0].numerator, 3);
+        assert_eq!(timeline.time_signatures[0].denominator, 4);
+        assert_eq!(MidiTimeline::track_count(&bytes).unwrap(), 2);
+    }
+
+    #[test]
+    fn missing_track_is_an_error() {
+        let bytes = midi_file(vec![vec![(0, note_on(0, 60, 100))]]);
+        assert!(MidiTimeline::parse(&bytes, 1).is_err());
+        assert!(MidiTimeline::parse(b"not a MIDI file", 0).is_err());
+    }
+
+    fn timeline(tempos: &[(u64, u32)], time_signatures: &[(u64, u8, u8)]) -> MidiTimeline {
+        MidiTimeline {
+            ticks_per_beat: TICKS_PER_BEAT,
+            length_ticks: 0,
+            notes: vec![],
+            cues: vec![],
+            tempos: tempos
+                .iter()
+                .map(|(tick, microseconds_per_beat)| TimelineTempo {
+                    tick: *tick,
+                    microseconds_per_beat: *microseconds_per_beat,
+                })
+                .collect(),
+            time_signatures: time_signatures
+                .iter()
+                .map(|(tick, numerator, denominator)| TimelineTimeSignature {
+                    tick: *tick,
+                    numerator: *numerator,
+                    denominator: *denominator,
+                })
+                .collect(),
+        }
+    }
+
+    fn assert_seconds(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1.0e-9,
+            "expected {} seconds, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn seconds_follow_tempo_changes() {
+        let timeline = timeline(&[(0, 500000), (960, 250000)], &[]);
+
+        assert_seconds(timeline.seconds_at(480), 0.5);
+        assert_seconds(timeline.seconds_at(960), 1.0);
+        assert_seconds(timeline.seconds_at(1440), 1.25);
+        assert_eq!(timeline.tick_at_seconds(0.5), 480);
+        assert_eq!(timeline.tick_at_seconds(1.0), 960);
+        assert_eq!(timeline.tick_at_seconds(1.25), 1440);
+        assert_eq!(timeline.microseconds_per_beat_at(959), 500000);
+        assert_eq!(timeline.microseconds_per_beat_at(960), 250000);
+    }
+
+    #[test]
+    fn default_tempo_is_120_bpm() {
+        let timeline = timeline(&[], &[]);
+
+        assert_seconds(timeline.seconds_at(960), 1.0);
+        assert_eq!(timeline.tick_at_seconds(2.0), 1920);
+    }
+
+    #[test]
+    fn jumps_are_told_apart_from_playing_through() {
+        let timeline = timeline(&[(0, 500000), (1920, 250000)], &[]);
+
+        // A frame's worth of playback, and a little ahead of it
+        assert!(!timeline.is_jump(480, 488, 1.0 / 60.0));
+        assert!(!timeline.is_jump(480, 600, 1.0 / 60.0));
+        // Seeking forward to a later anchor, and back to a loop start
+        assert!(timeline.is_jump(480, 1920, 1.0 / 60.0));
+        assert!(timeline.is_jump(1920, 480, 1.0 / 60.0));
+        // The same distance is played through when enough time has passed, at the tempo in effect
+        assert!(!timeline.is_jump(480, 1920, 1.5));
+        assert!(timeline.is_jump(1920, 3840, 0.2));
+        assert!(!timeline.is_jump(1920, 3840, 0.8));
+        // Nothing moves while paused
+        assert!(!timeline.is_jump(480, 480, 0.0));
+    }
+
+    #[test]
+    fn bar_beat_counts_in_the_time_signature() {
+        let three_four = timeline(&[], &[(0, 3, 4)]);
+
+        let bar_beat = three_four.bar_beat_at(1440);
+        assert_eq!((bar_beat.bar, bar_beat.beat), (1, 0));
+        let bar_beat = three_four.bar_beat_at(1440 + 480 + 240);
+        assert_eq!((bar_beat.bar, bar_beat.beat), (1, 1));
+        assert_eq!(bar_beat.beat_fraction, 0.5);
+        assert_eq!(bar_beat.time_signature, (3, 4));
+        // Beats of a 6/8 bar are eighth notes
+        let six_eight = timeline(&[], &[(0, 6, 8)]);
+        let bar_beat = six_eight.bar_beat_at(1440 + 240);
+        assert_eq!((bar_beat.bar, bar_beat.beat), (1, 1));
+    }
+
+    #[test]
+    fn partial_bar_before_a_signature_change_counts_as_a_bar() {
+        // The 3/4 section starts a quarter of the way into the second 4/4 bar
+        let timeline = timeline(&[], &[(0, 4, 4), (2400, 3, 4)]);
+
+        let bar_beat = timeline.bar_beat_at(2399);
+        assert_eq!((bar_beat.bar, bar_beat.beat), (1, 0));
+        assert_eq!(bar_beat.time_signature, (4, 4));
+        let bar_beat = timeline.bar_beat_at(2400);
+        assert_eq!((bar_beat.bar, bar_beat.beat), (2, 0));
+        assert_eq!(bar_beat.time_signature, (3, 4));
+        let bar_beat = timeline.bar_beat_at(2400 + 1440);
+        assert_eq!((bar_beat.bar, bar_beat.beat), (3, 0));
+    }
+
+    #[test]
+    fn next_boundaries_stop_at_signature_changes() {
+        let timeline = timeline(&[], &[(0, 4, 4), (2400, 3, 4)]);
+
+        assert_eq!(timeline.next_beat_tick(0), 480);
+        assert_eq!(timeline.next_beat_tick(100), 480);
+        assert_eq!(timeline.next_bar_tick(0), 1920);
+        assert_eq!(timeline.next_bar_tick(1920), 2400);
+        assert_eq!(timeline.next_beat_tick(2300), 2400);
+        assert_eq!(timeline.next_beat_tick(2400), 2880);
+        assert_eq!(timeline.next_bar_tick(2400), 3840);
+    }
+
+    #[test]
+    fn tick_at_bar_beat_inverts_bar_beat_at() {
+        let timeline = timeline(&[], &[(0, 4, 4), (2400, 3, 4)]);
+
+        assert_eq!(timeline.tick_at_bar_beat(0, 3), 1440);
+        assert_eq!(timeline.tick_at_bar_beat(2, 0), 2400);
+        assert_eq!(timeline.tick_at_bar_beat(3, 1), 3840 + 480);
+        for tick in [0, 480, 1920, 2400, 2880, 4320] {
+            let bar_beat = timeline.bar_beat_at(tick);
+            assert_eq!(timeline.tick_at_bar_beat(bar_beat.bar, bar_beat.beat), tick);
+        }
+    }
+}
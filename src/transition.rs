@@ -0,0 +1,59 @@
+use std::time::Duration;
+
+/// How to move from the playing program to a new one.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ProgramTransition {
+    #[default]
+    Immediate,
+    /// Fade the new program in while the current one fades out over the given duration.
+    Crossfade(Duration),
+    /// Fade the current program out, then switch and fade the new program in.
+    FadeOutIn {
+        fade_out: Duration,
+        fade_in: Duration,
+    },
+    /// Switch once the current program's MIDI playback reaches the next beat.
+    NextBeat,
+    /// Switch once the current program's MIDI playback reaches the next bar.
+    NextBar,
+}
+
+pub(crate) struct ActiveTransition {
+    pub program_no: usize,
+    pub transition: ProgramTransition,
+    pub stage: TransitionStage,
+    // Time spent in the current stage
+    pub elapsed: Duration,
+}
+
+pub(crate) enum TransitionStage {
+    Starting,
+    Crossfading,
+    FadingOut,
+    FadingIn,
+    WaitingForBoundary { start_index: u64 },
+}
+
+impl ActiveTransition {
+    pub fn new(program_no: usize, transition: ProgramTransition) -> Self {
+        Self {
+            program_no,
+            transition,
+            stage: TransitionStage::Starting,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub fn enter_stage(&mut self, stage: TransitionStage) {
+        self.stage = stage;
+        self.elapsed = Duration::ZERO;
+    }
+
+    // Progress through the current stage given its length, from 0 to 1
+    pub fn progress(&self, stage_duration: Duration) -> f32 {
+        if stage_duration.is_zero() {
+            return 1.0;
+        }
+        (self.elapsed.as_secs_f32() / stage_duration.as_secs_f32()).min(1.0)
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{
+    MidiGraphPlayers, MusicClock, NodeRef,
+    message::{MidiGraphCommand, MidiGraphCommandFailed, TweenParameter},
+};
+use bevy::{
+    math::curve::{Curve, EaseFunction},
+    prelude::*,
+};
+use midi_graph::{Balance, Event, effect::ModulationProperty};
+use std::time::Duration;
+
+/// A node parameter that can be tweened.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TweenedParameter {
+    Volume,
+    /// Pan from -1 (left) to 1 (right).
+    Pan,
+    Modulation(ModulationProperty),
+}
+
+impl TweenedParameter {
+    fn event(&self, value: f32) -> Event {
+        match self {
+            TweenedParameter::Volume => Event::Volume(value),
+            TweenedParameter::Pan => Event::SourceBalance(Balance::Pan(value)),
+            TweenedParameter::Modulation(property) => Event::Modulate(property.clone(), value),
+        }
+    }
+}
+
+/// How long a tween takes to reach its target.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TweenLength {
+    /// Measured in Bevy [`Time`].
+    Time(Duration),
+    /// Measured in quarter-note beats played by the [`MusicClock`], so the tween speeds up and
+    /// slows down with the music, and waits while it isn't playing.
+    Beats(f32),
+}
+
+// Tweens are told apart by how their node was given, so a node tweened by id and by name at the
+// same time has two tweens.
+struct ActiveTween {
+    player: Option<Entity>,
+    node: NodeRef,
+    parameter: TweenedParameter,
+    from: f32,
+    to: f32,
+    length: TweenLength,
+    easing: EaseFunction,
+    // Seconds or beats, following the tween length
+    elapsed: f32,
+    value: f32,
+    is_finished: bool,
+}
+
+impl ActiveTween {
+    fn targets(
+        &self,
+        player: Option<Entity>,
+        node: &NodeRef,
+        parameter: &TweenedParameter,
+    ) -> bool {
+        self.player == player && self.node == *node && self.parameter == *parameter
+    }
+
+    fn progress(&self) -> f32 {
+        let total = match self.length {
+            TweenLength::Time(duration) => duration.as_secs_f32(),
+            TweenLength::Beats(beats) => beats,
+        };
+        if total <= 0.0 {
+            return 1.0;
+        }
+        (self.elapsed / total).clamp(0.0, 1.0)
+    }
+}
+
+// Tweens in progress, along with the values of finished ones so that later tweens of the same
+// parameter can start where they left off.
+#[derive(Resource, Default)]
+pub struct ParameterTweens {
+    tweens: Vec<ActiveTween>,
+    // Program and beat position of the music clock last frame, if it was running
+    clock_position: Option<(Option<usize>, f64)>,
+}
+
+impl ParameterTweens {
+    pub fn advance_tweens(
+        time: Res<Time>,
+        clock: Res<MusicClock>,
+        players: Res<MidiGraphPlayers>,
+        mut tweens: ResMut<ParameterTweens>,
+        mut tween_requests: ResMut<Messages<TweenParameter>>,
+        mut graph_commands: MessageWriter<MidiGraphCommand>,
+        mut command_failures: MessageWriter<MidiGraphCommandFailed>,
+    ) {
+        for request in tween_requests.drain() {
+            tweens.start(request);
+        }
+        let delta_seconds = time.delta_secs();
+        let delta_beats = tweens.clock_beats_passed(&clock);
+        for tween in tweens.tweens.iter_mut() {
+            if tween.is_finished {
+                continue;
+            }
+            tween.elapsed += match tween.length {
+                TweenLength::Time(_) => delta_seconds,
+                TweenLength::Beats(_) => delta_beats,
+            };
+            tween.value = tween.from
+                + (tween.to - tween.from) * tween.easing.sample_clamped(tween.progress());
+            tween.is_finished = tween.progress() >= 1.0;
+            let command =
+                MidiGraphCommand::to_node(tween.node.clone(), tween.parameter.event(tween.value));
+            match tween.player {
+                None => {
+                    graph_commands.write(command);
+                }
+                Some(entity) if players.is_playing(entity) => {
+                    if let Err(error) = players.send(entity, command) {
+                        command_failures.write(MidiGraphCommandFailed { error });
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+        // Finished tweens are kept for their final values while their players are around
+        tweens
+            .tweens
+            .retain(|tween| tween.player.is_none_or(|entity| players.is_playing(entity)));
+    }
+
+    // Beats the clock has played since the last frame. None pass while it is stopped, and a
+    // change of program or a jump backwards, such as a loop, counts as none.
+    fn clock_beats_passed(&mut self, clock: &MusicClock) -> f32 {
+        let position = clock
+            .is_running()
+            .then(|| (clock.program_no(), clock.beats()));
+        let last = std::mem::replace(&mut self.clock_position, position);
+        match (last, position) {
+            (Some((last_program_no, last_beats)), Some((program_no, beats)))
+                if program_no == last_program_no =>
+            {
+                (beats - last_beats).max(0.0) as f32
+            }
+            _ => 0.0,
+        }
+    }
+
+    // A new tween replaces any tween of the same parameter, starting from wherever that one had
+    // got to
+    fn start(&mut self, request: TweenParameter) {
+        let existing = self
+            .tweens
+            .iter()
+            .position(|tween| tween.targets(request.player, &request.node, &request.parameter))
+            .map(|index| self.tweens.swap_remove(index));
+        let from = existing.map_or(request.from, |tween| tween.value);
+        self.tweens.push(ActiveTween {
+            player: request.player,
+            node: request.node,
+            parameter: request.parameter,
+            from,
+            to: request.to,
+            length: request.length,
+            easing: request.easing,
+            elapsed: 0.0,
+            value: from,
+            is_finished: false,
+        });
+    }
+}